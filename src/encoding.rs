@@ -0,0 +1,169 @@
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+
+/// How much of a file is looked at to detect its encoding.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// An `--encoding` label, named as in the WHATWG Encoding Standard (`utf-16le`, `shift_jis`, `latin1`, ...).
+pub fn parse(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+/// The encoding a file is read with: `forced` if given, otherwise detected, falling back to UTF-8.
+pub fn for_file(path: &Path, forced: Option<&'static Encoding>) -> &'static Encoding {
+    forced.or_else(|| detect(path)).unwrap_or(UTF_8)
+}
+
+/// Guesses a file's encoding from its start, or `None` if it looks like binary data.
+///
+/// A byte order mark decides outright; otherwise UTF-8 is taken as UTF-8, ASCII-heavy UTF-16 is recognised by
+/// its zero bytes, and anything else is left to chardetng's guess among the legacy encodings.
+pub fn detect(path: &Path) -> Option<&'static Encoding> {
+    let mut sample = Vec::new();
+    fs::File::open(path).ok()?.take(SAMPLE_SIZE + 1).read_to_end(&mut sample).ok()?;
+    let complete = sample.len() as u64 <= SAMPLE_SIZE;
+    sample.truncate(SAMPLE_SIZE as usize);
+    detect_sample(&sample, complete)
+}
+
+/// `detect` for the first bytes of a file, `complete` if they are all of it.
+fn detect_sample(sample: &[u8], complete: bool) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(sample) {
+        return Some(encoding);
+    }
+    if let Some(encoding) = utf16_without_bom(sample) {
+        return Some(encoding);
+    }
+    if looks_binary(sample) {
+        return None;
+    }
+    // A stray byte in otherwise UTF-8 text is a damaged line, not a sign of a legacy encoding, which rarely forms
+    // valid multi-byte sequences by chance.
+    let (multibyte, invalid) = utf8_sequences(sample, complete);
+    if invalid == 0 || invalid <= multibyte {
+        return Some(UTF_8);
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(sample, complete);
+    Some(detector.guess(None, false))
+}
+
+/// Counts the valid multi-byte UTF-8 characters and the invalid sequences in `bytes`. A character cut off at the
+/// end only counts as invalid if `complete` says the file ends there.
+fn utf8_sequences(mut bytes: &[u8], complete: bool) -> (usize, usize) {
+    let multibyte_in = |text: &[u8]| std::str::from_utf8(text).map_or(0, |text| text.chars().filter(|c| c.len_utf8() > 1).count());
+    let (mut multibyte, mut invalid) = (0, 0);
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(_) => return (multibyte + multibyte_in(bytes), invalid),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                multibyte += multibyte_in(valid);
+                match e.error_len() {
+                    Some(len) => {
+                        invalid += 1;
+                        bytes = &rest[len..];
+                    }
+                    None => return (multibyte, invalid + usize::from(complete)),
+                }
+            }
+        }
+    }
+}
+
+/// UTF-16 without a BOM, recognised by the zero high bytes of its ASCII characters all falling on the same side.
+fn utf16_without_bom(sample: &[u8]) -> Option<&'static Encoding> {
+    let pairs = sample.len() / 2;
+    if pairs < 2 {
+        return None;
+    }
+    let (mut high_first, mut high_last) = (0, 0);
+    for pair in sample.chunks_exact(2) {
+        match (pair[0], pair[1]) {
+            (0, 0) => return None,
+            (0, _) => high_first += 1,
+            (_, 0) => high_last += 1,
+            _ => {}
+        }
+    }
+    match (high_first, high_last) {
+        (0, n) if n * 2 > pairs => Some(UTF_16LE),
+        (n, 0) if n * 2 > pairs => Some(UTF_16BE),
+        _ => None,
+    }
+}
+
+/// NUL bytes, or more than a few control characters other than whitespace and escape, mean the data is not text.
+fn looks_binary(sample: &[u8]) -> bool {
+    let controls = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    sample.contains(&0) || controls * 32 > sample.len()
+}
+
+/// A file's contents as UTF-8 in the given encoding, without a BOM. UTF-8 passes through untouched, so invalid bytes
+/// still reach the caller; other encodings are transcoded, with undecodable bytes becoming U+FFFD.
+pub fn reader(path: &Path, encoding: &'static Encoding) -> io::Result<impl BufRead> {
+    let file = fs::File::open(path)?;
+    // Passthru only happens without an explicit encoding, and sniffing must not pick another one instead.
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(Some(encoding).filter(|&encoding| encoding != UTF_8))
+        .utf8_passthru(true)
+        .bom_sniffing(false)
+        .strip_bom(true)
+        .build(file);
+    Ok(io::BufReader::new(decoder))
+}
+
+/// A file's whole contents as UTF-8, read in the given encoding.
+pub fn read_to_string(path: &Path, encoding: &'static Encoding) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    reader(path, encoding)?.read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_byte_order_marks() {
+        assert_eq!(detect_sample(b"\xEF\xBB\xBFplain", true), Some(UTF_8));
+        assert_eq!(detect_sample(b"\xFF\xFEa\0b\0", true), Some(UTF_16LE));
+        assert_eq!(detect_sample(b"\xFE\xFF\0a\0b", true), Some(UTF_16BE));
+    }
+
+    #[test]
+    fn detects_utf16_without_bom() {
+        let text: Vec<u16> = "second line foo\n".encode_utf16().collect();
+        let le: Vec<u8> = text.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+        let be: Vec<u8> = text.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+        assert_eq!(detect_sample(&le, true), Some(UTF_16LE));
+        assert_eq!(detect_sample(&be, true), Some(UTF_16BE));
+    }
+
+    #[test]
+    fn detects_latin1() {
+        let latin1: Vec<u8> = "café crème brûlée, déjà vu à la française\n".repeat(3).chars().map(|c| c as u8).collect();
+        assert_eq!(detect_sample(&latin1, true), Some(encoding_rs::WINDOWS_1252));
+    }
+
+    #[test]
+    fn keeps_utf8_with_a_stray_byte() {
+        assert_eq!(detect_sample("café naïve\n— dash —\n".as_bytes(), true), Some(UTF_8));
+        assert_eq!(detect_sample(b"caf\xC3\xA9 na\xC3\xAFve\n\xE2\x80\x94 dash \xE2\x80\x94\nbad \xFF byte\n", true), Some(UTF_8));
+        // Cut off mid-character by the sample size rather than damaged.
+        assert_eq!(detect_sample(b"caf\xC3", false), Some(UTF_8));
+    }
+
+    #[test]
+    fn rejects_binary() {
+        assert_eq!(detect_sample(&(0..=255).collect::<Vec<u8>>(), true), None);
+    }
+}
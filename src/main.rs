@@ -1,37 +1,66 @@
-use clap::{Arg, ArgAction, Command};
-use std::path::{Path, PathBuf};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::ffi::OsStr;
-use std::io::{self, BufRead};
-use std::collections::HashMap;
-use regex::Regex;
-use chrono::{DateTime, Utc};
+use std::io::{self, BufRead, Write};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use matcher::{MatchOptions, Matcher};
+use encoding_rs::Encoding;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, Utc};
 use colored::*;
-use std::os::unix::fs::{FileTypeExt, PermissionsExt};
-
-#[derive(Debug)]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+mod encoding;
+mod matcher;
+mod replace;
+mod select;
+mod sort;
+mod template;
+mod tui;
+mod watch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum SortField {
     Name,
     Date,
     Size,
     Type,
     Ext,
+    Natural,
+    Version,
+    Lines,
+    Tokens,
+    GitDate,
+    Created,
+    Group,
 }
 
 impl SortField {
-    fn from_str(s: &str) -> Self {
-        match s {
+    fn from_str(s: &str) -> Option<Self> {
+        Some(match s {
+            "name" => SortField::Name,
             "date" => SortField::Date,
             "size" => SortField::Size,
             "type" => SortField::Type,
             "ext" => SortField::Ext,
-            _ => SortField::Name
-        }
+            "natural" => SortField::Natural,
+            "version" => SortField::Version,
+            "lines" => SortField::Lines,
+            "tokens" => SortField::Tokens,
+            "git-date" => SortField::GitDate,
+            "created" => SortField::Created,
+            "group" => SortField::Group,
+            _ => return None,
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum SortDirection {
     Asc,
     Desc,
@@ -46,40 +75,245 @@ impl SortDirection {
     }
 }
 
+const SORT_FIELD_NAMES: &str = "name, natural, version, date, created, git-date, size, lines, tokens, type, ext, group";
+
+/// One comparator in a `--sort` chain such as `ext,size:desc,name`.
+#[derive(Debug, Clone, Copy)]
+struct SortKey {
+    field: SortField,
+    direction: SortDirection,
+}
+
+impl SortKey {
+    /// Parses a comma-separated `FIELD[:asc|desc]` list; keys without a direction use `default_direction`.
+    fn parse_list(list: &str, default_direction: SortDirection) -> Result<Vec<Self>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (field, direction) = match key.split_once(':') {
+                    Some((field, "asc")) => (field, SortDirection::Asc),
+                    Some((field, "desc")) => (field, SortDirection::Desc),
+                    Some((_, direction)) => return Err(format!("invalid sort direction '{}' (expected asc or desc)", direction)),
+                    None => (key, default_direction),
+                };
+                let field = SortField::from_str(field).ok_or_else(|| {
+                    format!("unknown sort field '{}' (expected one of: {})", field, SORT_FIELD_NAMES)
+                })?;
+                Ok(SortKey { field, direction })
+            })
+            .collect()
+    }
+}
+
+/// Which timestamp is displayed and used by `--sort date`.
+#[derive(Debug, Clone, Copy)]
+enum TimeField {
+    Mtime,
+    Atime,
+    Ctime,
+    Birth,
+}
+
+impl TimeField {
+    fn from_str(s: &str) -> Self {
+        match s {
+            "atime" => TimeField::Atime,
+            "ctime" => TimeField::Ctime,
+            "birth" => TimeField::Birth,
+            _ => TimeField::Mtime
+        }
+    }
+
+    fn of(self, metadata: &fs::Metadata) -> Option<SystemTime> {
+        match self {
+            TimeField::Mtime => metadata.modified().ok(),
+            TimeField::Atime => metadata.accessed().ok(),
+            TimeField::Ctime => u64::try_from(metadata.ctime()).ok()
+                .map(|secs| UNIX_EPOCH + Duration::new(secs, metadata.ctime_nsec() as u32)),
+            TimeField::Birth => metadata.created().ok(),
+        }
+    }
+}
+
+/// How timestamps are printed.
+#[derive(Debug)]
+enum TimeStyle {
+    /// RFC 3339 in UTC (the default).
+    Iso,
+    /// RFC 3339 in the local time zone.
+    Local,
+    /// "3 days ago".
+    Relative,
+    /// The local calendar date.
+    Date,
+    /// A strftime format, applied in the local time zone.
+    Custom(String),
+}
+
+impl TimeStyle {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "iso" => Ok(TimeStyle::Iso),
+            "local" => Ok(TimeStyle::Local),
+            "relative" => Ok(TimeStyle::Relative),
+            "date" => Ok(TimeStyle::Date),
+            _ => {
+                let format = s.strip_prefix('+').unwrap_or(s);
+                if !format.contains('%') {
+                    return Err(format!("unknown time style '{}' (expected iso, local, relative, date or a strftime format)", s));
+                }
+                if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                    return Err(format!("invalid time format '{}'", format));
+                }
+                Ok(TimeStyle::Custom(format.to_string()))
+            }
+        }
+    }
+}
+
+/// How sizes are printed: binary (KiB, MiB, ...), decimal (kB, MB, ...) or plain byte counts.
+#[derive(Debug, Clone, Copy)]
+enum SizeStyle {
+    Iec,
+    Si,
+    Bytes,
+}
+
+impl SizeStyle {
+    fn from_str(s: &str) -> Self {
+        match s {
+            "si" => SizeStyle::Si,
+            "bytes" => SizeStyle::Bytes,
+            _ => SizeStyle::Iec
+        }
+    }
+
+    /// The widest value `format_size` produces in this style (`1023.9 KiB`, a byte count in the terabytes),
+    /// so size columns can be right-aligned.
+    fn width(self) -> usize {
+        match self {
+            SizeStyle::Iec => 10,
+            SizeStyle::Si => 9,
+            SizeStyle::Bytes => 13,
+        }
+    }
+}
+
+/// The `group:` type filters, in the order `--sort group` lists them.
+const FILE_TYPE_GROUPS: &[(&str, &[&str])] = &[
+    ("web", &["html","htm","css","scss","less","js","jsx","ts","tsx"]),
+    ("docs", &["md","txt","pdf","doc","docx","odt","rtf"]),
+    ("images", &["jpg","jpeg","png","gif","svg","webp","bmp"]),
+    ("code", &["py","java","cpp","c","h","hpp","cs","go","rs","php","rb","pl","scala","kt","swift"]),
+    ("config", &["json","yaml","yml","toml","ini","conf","xml"]),
+    ("data", &["csv","sql","db","sqlite"]),
+    ("script", &["sh","bash","zsh","fish","ps1","bat","cmd"]),
+];
+
 fn file_type_groups() -> HashMap<&'static str, Vec<&'static str>> {
-    let mut groups = HashMap::new();
-    groups.insert("web", vec!["html","htm","css","scss","less","js","jsx","ts","tsx"]);
-    groups.insert("docs", vec!["md","txt","pdf","doc","docx","odt","rtf"]);
-    groups.insert("images", vec!["jpg","jpeg","png","gif","svg","webp","bmp"]);
-    groups.insert("code", vec!["py","java","cpp","c","h","hpp","cs","go","rs","php","rb","pl","scala","kt","swift"]);
-    groups.insert("config", vec!["json","yaml","yml","toml","ini","conf","xml"]);
-    groups.insert("data", vec!["csv","sql","db","sqlite"]);
-    groups.insert("script", vec!["sh","bash","zsh","fish","ps1","bat","cmd"]);
-    groups
+    FILE_TYPE_GROUPS.iter().map(|(name, exts)| (*name, exts.to_vec())).collect()
+}
+
+/// Position of an extension's group in `FILE_TYPE_GROUPS`; extensions in no group come last.
+fn group_rank(ext: Option<&str>) -> usize {
+    ext.and_then(|ext| FILE_TYPE_GROUPS.iter().position(|(_, exts)| exts.contains(&ext)))
+        .unwrap_or(FILE_TYPE_GROUPS.len())
+}
+
+/// `-l` prints the paths of matching files, `--count` every text file's path with its number of matching lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchListing {
+    Paths,
+    Counts,
+}
+
+/// A `--depth-for PATH=N[:skip]` rule: the subtree at `path` (relative to the root) is listed `depth` levels deep
+/// from `path` itself instead of following `--depth`. `:skip` lists only its top level, whatever N is.
+#[derive(Debug)]
+struct DepthOverride {
+    path: PathBuf,
+    depth: usize,
+}
+
+impl DepthOverride {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid --depth-for '{}' (expected PATH=N or PATH=N:skip)", spec);
+        let (path, rule) = spec.rsplit_once('=').ok_or_else(invalid)?;
+        let (depth, skip) = match rule.split_once(':') {
+            Some((depth, "skip")) => (depth, true),
+            Some(_) => return Err(invalid()),
+            None => (rule, false),
+        };
+        let depth: usize = depth.parse().map_err(|_| invalid())?;
+        // `./src/` and `src` name the same subtree.
+        let path: PathBuf = Path::new(path).components().filter(|c| !matches!(c, Component::CurDir)).collect();
+        if path.as_os_str().is_empty() {
+            return Err(invalid());
+        }
+        Ok(DepthOverride { path, depth: if skip { 1 } else { depth } })
+    }
 }
 
 #[derive(Debug)]
 struct Config {
     max_depth: usize,
-    project_dir: PathBuf,
+    depth_overrides: Vec<DepthOverride>,
+    max_entries: usize,
+    roots: Vec<PathBuf>,
     exclude_dirs: Vec<String>,
     output_format: String,
     show_content: bool,
     max_file_size: u64,
     file_types: Vec<String>,
-    sort_by: SortField,
-    sort_direction: SortDirection,
+    sort_keys: Vec<SortKey>,
+    sort_ignore_case: bool,
     sort_dirs_first: bool,
-    content_filter: Option<Regex>,
-    content_context: usize,
+    content_filter: Option<Matcher>,
+    match_options: MatchOptions,
+    context_before: usize,
+    context_after: usize,
     whole_file: bool,
+    /// `--encoding`: read every text file in this encoding instead of detecting it.
+    encoding: Option<&'static Encoding>,
+    /// `--max-count`: matches shown per file (0 = unlimited).
+    max_count: usize,
+    /// `--max-total-matches`: matches shown in one render (0 = unlimited), and how many have been shown so far.
+    max_total_matches: usize,
+    matches_shown: Cell<usize>,
     highlight: bool,
     groups: HashMap<&'static str, Vec<&'static str>>,
+    selected: Option<HashSet<PathBuf>>,
+    file_list: Option<HashMap<PathBuf, BTreeSet<PathBuf>>>,
+    follow_links: bool,
+    one_file_system: bool,
+    flat: bool,
+    prune: bool,
+    matching_only: bool,
+    /// `--replace`: matching lines are shown as a diff against the line with this replacement applied.
+    replacement: Option<String>,
+    match_listing: Option<MatchListing>,
+    /// Matching lines per file for `--matching-only`, `-l` and `--count`, so lookahead and listing read each file once per render.
+    match_counts: RefCell<HashMap<PathBuf, usize>>,
+    /// Whether a directory has a shown file below it, for `--prune` and `--matching-only`, so each level's lookahead
+    /// reuses the answers for the directories below instead of walking them again.
+    visible_dirs: RefCell<HashMap<PathBuf, bool>>,
+    collapse: bool,
+    template: Option<template::Template>,
+    user_names: HashMap<u32, String>,
+    group_names: HashMap<u32, String>,
+    time_style: TimeStyle,
+    time_field: TimeField,
+    size_style: SizeStyle,
+    /// Last commit time of every file and directory under the roots, read from `git log` the first time `--sort git-date` needs it.
+    git_dates: OnceCell<HashMap<PathBuf, SystemTime>>,
 }
 
-fn main() {
-    let matches = Command::new("rs")
+/// The command line, as `main` parses it.
+fn cli() -> Command {
+    Command::new("rs")
         .version("1.0")
+        .args_conflicts_with_subcommands(true)
         .about("Maps and displays the source tree with syntax highlighting.")
         .long_about(
 r#"Maps and displays the source tree with syntax highlighting, filtering, and sorting options.
@@ -100,6 +334,34 @@ Examples:
   rs -t ext:py -t group:web ./src
       Filter by more than one type (Python files OR files in the web group)
       Note: Append multiple '-t' flags for multiple filters instead of using a delimiter.
+
+  rs -d 2 --watch
+      Re-render the tree two levels deep every time a file is created, modified or removed
+
+  rs -d 0 --select --save-selection picked.txt > context.md
+      Pick files with a fuzzy finder and write the tree plus their contents to context.md
+
+  rs --columns name,size,lines,tokens -d 0 src
+      Show line and (estimated) token counts next to each file
+
+  rs --template '{name} {size:b} {mtime:%Y-%m-%d} {hash:12}'
+      Format every file line yourself
+
+  rs --sort date --direction desc --time-style relative --time-field ctime
+      Show the most recently changed entries first, with "3 days ago" style timestamps
+
+  rs -d 0 -x /
+      Map the whole root file system without wandering into /proc, /sys or other mounts
+
+  rs src tests docs
+      Map several directories in one run, each under its own header
+
+  git ls-files -z | rs --files-from -
+      Render exactly the tracked files (with contents) instead of walking the directory
+
+  rs tui ./src
+      Browse ./src interactively: expand directories, preview and search contents,
+      and export marked files as markdown
 "#
         )
         .after_help(
@@ -116,28 +378,70 @@ r#"Type Filters:
     pipe            Show pipes
     executable      Show executable files
     symlink         Show symbolic links
+    broken          Show symbolic links whose target does not exist
     device          Show device files
     hidden          Show hidden files
     empty           Show empty files
     archive         Show archive files
+    setuid          Show files with the setuid bit
+    setgid          Show files with the setgid bit
+    sticky          Show files with the sticky bit
+
+  Size:
+    size:>N, size:<N, size:>=N, size:<=N, size:N
+                    Show files by size; N takes the same units sizes are printed in
+                    (e.g. size:>1.5MiB, size:<10kB, size:0)
+
+  Ownership and Permissions:
+    owner:USER      Show files owned by USER (name or numeric uid)
+    perm:MODE       Show files with all bits of MODE set: world-writable,
+                    world-readable, world-executable, group-writable, or octal (e.g. perm:4000)
+
+Columns and templates (--columns LIST, --template TEMPLATE):
+  Fields: name, path, size, mtime, atime, ctime, mode, owner, group, inode,
+          nlink, mime, lang, lines, tokens (estimated), hash (SHA-256)
+  Specs:  {size:h} human-readable (default), {size:b} bytes,
+          {mode} is ls -l style (-rwsr-xr-x), {mode:o} octal,
+          {owner} and {group} are names, {owner:n} and {group:n} numeric ids,
+          {mtime:%Y-%m-%d} any strftime format (also atime, ctime),
+          {hash:12} first 12 hex digits
+  '{{' and '}}' produce literal braces.
 "#
         )
         .arg(
             Arg::new("directory")
-                .help("Directory to start mapping from")
+                .help("Directories to start mapping from (each is rendered as its own section)")
                 .default_value(".")
-                .num_args(1)
+                .num_args(1..)
         )
         .arg(
             Arg::new("depth")
+                .global(true)
                 .short('d')
                 .long("depth")
                 .help("Maximum directory depth (0 = unlimited)")
                 .num_args(1)
                 .default_value("1")
         )
+        .arg(
+            Arg::new("depth_for")
+                .global(true)
+                .long("depth-for")
+                .value_name("PATH=N[:skip]")
+                .help("Depth for the subtree at PATH, counted from PATH (0 = unlimited); :skip lists only its top level. Can be repeated")
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("max_entries")
+                .global(true)
+                .long("max-entries")
+                .help("Show at most N entries per directory, then a line summarizing the rest (0 = unlimited)")
+                .num_args(1)
+                .default_value("0")
+        )
         .arg(
             Arg::new("format")
+                .global(true)
                 .short('f')
                 .long("format")
                 .help("Output format: markdown or text")
@@ -146,6 +450,7 @@ r#"Type Filters:
         )
         .arg(
             Arg::new("exclude")
+                .global(true)
                 .short('e')
                 .long("exclude")
                 .help("Exclude directories or files by name (can be used multiple times)")
@@ -153,21 +458,33 @@ r#"Type Filters:
         )
         .arg(
             Arg::new("content")
+                .global(true)
                 .short('c')
                 .long("content")
                 .help("Show file contents in the tree")
                 .action(ArgAction::SetTrue)
+                .overrides_with("no_content")
+        )
+        .arg(
+            Arg::new("no_content")
+                .global(true)
+                .long("no-content")
+                .help("Don't show file contents (the default, except with --files-from)")
+                .action(ArgAction::SetTrue)
+                .overrides_with("content")
         )
         .arg(
             Arg::new("max_size")
+                .global(true)
                 .short('s')
                 .long("max-size")
-                .help("Maximum file size in bytes for content display")
+                .help("Maximum file size for content display, in bytes or with a unit (100k, 1.5MiB, 2MB)")
                 .num_args(1)
                 .default_value("100000")
         )
         .arg(
             Arg::new("type")
+                .global(true)
                 .short('t')
                 .long("type")
                 .help("Filter results by type (can be used multiple times)")
@@ -175,120 +492,653 @@ r#"Type Filters:
         )
         .arg(
             Arg::new("pattern")
+                .global(true)
                 .short('p')
                 .long("pattern")
-                .help("Show only content matching a specific pattern")
+                .help("Show only content matching a specific pattern (repeat to match any of several)")
+                .num_args(1)
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("and_pattern")
+                .global(true)
+                .long("and-pattern")
+                .help("Only show content of files that also match this pattern somewhere (can be repeated; all must match)")
+                .num_args(1)
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("multiline")
+                .global(true)
+                .short('U')
+                .long("multiline")
+                .help("Search each file as a whole so patterns can match across lines (\\n matches a line break)")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("replace")
+                .global(true)
+                .long("replace")
+                .value_name("TEMPLATE")
+                .help("Show matching lines as a diff against the line with each match replaced by TEMPLATE ($1, ${name} for capture groups)")
                 .num_args(1)
+                .requires("pattern")
+                .conflicts_with_all(["invert_match", "multiline"])
+        )
+        .arg(
+            Arg::new("matching_only")
+                .global(true)
+                .long("matching-only")
+                .help("Only list files whose content matches the pattern, and the directories leading to them")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("files_with_matches")
+                .global(true)
+                .short('l')
+                .long("files-with-matches")
+                .help("Only print the paths of files whose content matches the pattern, one per line")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("count")
+        )
+        .arg(
+            Arg::new("count")
+                .global(true)
+                .long("count")
+                .help("Print each text file's path with its number of matching lines (PATH:COUNT)")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("ignore_case")
+                .global(true)
+                .short('i')
+                .long("ignore-case")
+                .help("Match patterns case-insensitively")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("smart_case")
+                .global(true)
+                .long("smart-case")
+                .help("Match case-insensitively unless a pattern contains an uppercase letter")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("fixed_strings")
+                .global(true)
+                .short('F')
+                .long("fixed-strings")
+                .help("Treat patterns as literal text rather than regular expressions")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("word")
+                .global(true)
+                .short('w')
+                .long("word")
+                .help("Only match whole words")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("invert_match")
+                .global(true)
+                .short('v')
+                .long("invert-match")
+                .help("Show the lines that do not match the pattern")
+                .action(ArgAction::SetTrue)
         )
         .arg(
             Arg::new("context")
+                .global(true)
                 .long("context")
                 .help("Show N lines of context around matches")
                 .num_args(1)
                 .default_value("0")
         )
+        .arg(
+            Arg::new("after_context")
+                .global(true)
+                .short('A')
+                .long("after-context")
+                .help("Show N lines of context after matches (overrides --context)")
+                .num_args(1)
+        )
+        .arg(
+            Arg::new("before_context")
+                .global(true)
+                .short('B')
+                .long("before-context")
+                .help("Show N lines of context before matches (overrides --context)")
+                .num_args(1)
+        )
+        .arg(
+            Arg::new("max_count")
+                .global(true)
+                .long("max-count")
+                .help("Show at most N matches per file (0 = unlimited)")
+                .num_args(1)
+                .default_value("0")
+        )
+        .arg(
+            Arg::new("max_total_matches")
+                .global(true)
+                .long("max-total-matches")
+                .help("Show at most N matches in the whole run (0 = unlimited)")
+                .num_args(1)
+                .default_value("0")
+        )
         .arg(
             Arg::new("whole_file")
+                .global(true)
                 .long("whole-file")
                 .help("Show the entire file if any line matches")
                 .action(ArgAction::SetTrue)
         )
+        .arg(
+            Arg::new("encoding")
+                .global(true)
+                .long("encoding")
+                .help("Read text files in this encoding (utf-8, utf-16le, latin1, shift_jis, ...) instead of detecting it")
+                .num_args(1)
+        )
         .arg(
             Arg::new("highlight")
+                .global(true)
                 .long("highlight")
                 .help("Highlight matching content")
                 .action(ArgAction::SetTrue)
         )
         .arg(
             Arg::new("sort")
+                .global(true)
                 .long("sort")
-                .help("Sort by: name,date,size,type,ext")
+                .help("Sort by a comma-separated list of keys, each optionally :asc or :desc (e.g. ext,size:desc,name). Keys: name,natural,version,date,created,git-date,size,lines,tokens,type,ext,group")
                 .num_args(1)
                 .default_value("name")
         )
+        .arg(
+            Arg::new("sort_ignore_case")
+                .global(true)
+                .long("sort-ignore-case")
+                .help("Ignore case when sorting by name, natural or version")
+                .action(ArgAction::SetTrue)
+        )
         .arg(
             Arg::new("direction")
+                .global(true)
                 .long("direction")
-                .help("Sort direction: asc or desc")
+                .help("Sort direction for --sort keys without their own: asc or desc")
                 .num_args(1)
                 .default_value("asc")
         )
+        .arg(
+            Arg::new("size_style")
+                .global(true)
+                .long("size-style")
+                .help("Size format: iec (1.9 MiB), si (2.0 MB) or bytes")
+                .num_args(1)
+                .value_parser(["iec", "si", "bytes"])
+                .default_value("iec")
+        )
+        .arg(
+            Arg::new("time_style")
+                .global(true)
+                .long("time-style")
+                .help("Timestamp format: iso (UTC), local, relative, date, or a strftime format such as '+%d %b %H:%M'")
+                .value_name("STYLE")
+                .num_args(1)
+                .default_value("iso")
+        )
+        .arg(
+            Arg::new("time_field")
+                .global(true)
+                .long("time-field")
+                .help("Timestamp to display and sort by with --sort date")
+                .num_args(1)
+                .value_parser(["mtime", "atime", "ctime", "birth"])
+                .default_value("mtime")
+        )
         .arg(
             Arg::new("dirs_first")
+                .global(true)
                 .long("dirs-first")
                 .help("Show directories first")
                 .action(ArgAction::SetTrue)
         )
         .arg(
             Arg::new("no_dirs_first")
+                .global(true)
                 .long("no-dirs-first")
                 .help("Don't sort directories separately")
                 .action(ArgAction::SetTrue)
         )
-        .get_matches();
+        .arg(
+            Arg::new("follow")
+                .global(true)
+                .long("follow")
+                .help("Follow symbolic links: list link targets and descend into linked directories (cycles are detected)")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no_follow")
+        )
+        .arg(
+            Arg::new("no_follow")
+                .global(true)
+                .long("no-follow")
+                .help("List symbolic links as links without following them (default)")
+                .action(ArgAction::SetTrue)
+                .overrides_with("follow")
+        )
+        .arg(
+            Arg::new("one_file_system")
+                .global(true)
+                .short('x')
+                .long("one-file-system")
+                .help("Don't descend into directories on other file systems (mount points are still listed)")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("prune")
+                .global(true)
+                .long("prune")
+                .help("Hide directories with nothing to show below them; with type filters, directories are kept if any file inside matches")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("collapse")
+                .global(true)
+                .long("collapse")
+                .help("Show chains of directories that each contain only one directory on a single line (src/main/java/)")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("flat")
+                .global(true)
+                .long("flat")
+                .help("Print one path per line, relative to the directory, sorted across the whole tree instead of per directory")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("columns")
+                .global(true)
+                .long("columns")
+                .help("Comma-separated fields for each file line: name,size,mtime,atime,ctime,mode,owner,group,inode,nlink,mime,lang,lines,tokens,hash")
+                .value_name("LIST")
+                .num_args(1)
+                .conflicts_with("template")
+        )
+        .arg(
+            Arg::new("template")
+                .global(true)
+                .long("template")
+                .help("Format each file line from a template, e.g. '{name} {size:h} {mtime:%Y-%m-%d}'")
+                .value_name("TEMPLATE")
+                .num_args(1)
+        )
+        .arg(
+            Arg::new("files_from")
+                .long("files-from")
+                .help("Render the paths listed in FILE (or stdin with '-') instead of walking the directory")
+                .value_name("FILE")
+                .num_args(1)
+        )
+        .arg(
+            Arg::new("select")
+                .long("select")
+                .help("Pick the files whose contents are shown with an interactive fuzzy finder")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("save_selection")
+                .long("save-selection")
+                .help("Write the files picked with --select to a list file, one path per line")
+                .value_name("FILE")
+                .num_args(1)
+                .requires("select")
+        )
+        .arg(
+            Arg::new("apply")
+                .long("apply")
+                .help("Write the --replace changes to the files")
+                .action(ArgAction::SetTrue)
+                .requires("replace")
+                .conflicts_with("watch")
+        )
+        .arg(
+            Arg::new("backup")
+                .long("backup")
+                .help("With --apply, keep each original file as FILE.bak")
+                .action(ArgAction::SetTrue)
+                .requires("apply")
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Keep running and re-render the tree whenever files change")
+                .action(ArgAction::SetTrue)
+        )
+        .subcommand(
+            Command::new("tui")
+                .about("Browse the tree interactively with a live content preview")
+                .arg(
+                    Arg::new("directory")
+                        .help("Directory to start mapping from")
+                        .default_value(".")
+                        .num_args(1)
+                )
+        )
+}
+
+fn main() {
+    sort::init_locale();
+    let matches = cli().get_matches();
+
+    if let Some(tui_matches) = matches.subcommand_matches("tui") {
+        let config = build_config(tui_matches);
+        check_roots(&config);
+        if let Err(e) = tui::run(config) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut config = build_config(&matches);
+    check_roots(&config);
+
+    if let Some(source) = matches.get_one::<String>("files_from") {
+        if config.roots.len() > 1 {
+            eprintln!("Error: --files-from takes a single directory to resolve the listed paths against.");
+            std::process::exit(1);
+        }
+        match load_file_list(source, config.root()) {
+            Ok(list) => config.file_list = Some(list),
+            Err(e) => {
+                eprintln!("Error: cannot read file list '{}': {}", source, e);
+                std::process::exit(1);
+            }
+        }
+        // The list already decides what is shown: render all of it, with contents, unless told otherwise.
+        if matches.value_source("depth") != Some(ValueSource::CommandLine) {
+            config.max_depth = 0;
+        }
+        config.show_content = !matches.get_flag("no_content");
+    }
+
+    if matches.get_flag("select") {
+        let chosen = match select::run(&config) {
+            Ok(Some(chosen)) => chosen,
+            Ok(None) => std::process::exit(130),
+            Err(e) => {
+                eprintln!("Error: cannot run the file picker: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(list_file) = matches.get_one::<String>("save_selection") {
+            let list: String = chosen.iter()
+                .map(|p| format!("{}\n", display_path(&config, p)))
+                .collect();
+            if let Err(e) = fs::write(list_file, list) {
+                eprintln!("Error: cannot write selection to '{}': {}", list_file, e);
+                std::process::exit(1);
+            }
+        }
+        config.show_content = true;
+        config.selected = Some(chosen.into_iter().collect());
+    }
+
+    if matches.get_flag("watch") {
+        if let Err(e) = watch::run(&config) {
+            eprintln!("Error: cannot watch: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    render(&config);
+
+    if matches.get_flag("apply") {
+        replace::apply(&config, matches.get_flag("backup"));
+    }
+}
+
+/// Reads a newline- or NUL-separated list of paths (as printed by `git ls-files -z`, `fd -0` or `rg -l`)
+/// and indexes it by parent directory, adding every intermediate directory between `root` and each path.
+///
+/// Relative paths are taken relative to `root`; absolute paths outside it are skipped with a warning.
+fn load_file_list(source: &str, root: &Path) -> io::Result<HashMap<PathBuf, BTreeSet<PathBuf>>> {
+    let mut data = Vec::new();
+    if source == "-" {
+        io::Read::read_to_end(&mut io::stdin(), &mut data)?;
+    } else {
+        data = fs::read(source)?;
+    }
+
+    let separator = if data.contains(&0) { b'\0' } else { b'\n' };
+    // Absolute paths (from `fd -a` or `find $PWD`) are compared with the root as resolved, not as typed.
+    let canonical_root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let mut list: HashMap<PathBuf, BTreeSet<PathBuf>> = HashMap::new();
+    for raw in data.split(|&b| b == separator) {
+        let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+        if raw.is_empty() {
+            continue;
+        }
+        let listed = Path::new(OsStr::from_bytes(raw));
+        let rel: PathBuf = if listed.is_absolute() {
+            let resolved = sort::canonical_path(listed).unwrap_or_else(|| listed.to_path_buf());
+            match resolved.strip_prefix(&canonical_root).or_else(|_| listed.strip_prefix(root)) {
+                Ok(rel) => rel.to_path_buf(),
+                Err(_) => {
+                    eprintln!("Warning: '{}' is outside '{}', skipping", listed.display(), root.display());
+                    continue;
+                }
+            }
+        } else {
+            listed.to_path_buf()
+        };
 
-    let project_dir = PathBuf::from(matches.get_one::<String>("directory").unwrap());
+        let mut path = root.to_path_buf();
+        for component in rel.components().filter(|c| !matches!(c, std::path::Component::CurDir)) {
+            let child = path.join(component);
+            list.entry(path).or_default().insert(child.clone());
+            path = child;
+        }
+    }
+    Ok(list)
+}
+
+/// Builds the run configuration from parsed arguments (the top-level command or the `tui` subcommand).
+fn build_config(matches: &ArgMatches) -> Config {
+    let roots: Vec<PathBuf> = matches.get_many::<String>("directory")
+        .map(|vals| vals.map(PathBuf::from).collect())
+        .unwrap_or_default();
     let max_depth = matches.get_one::<String>("depth")
         .and_then(|v| v.parse().ok())
         .unwrap_or(1);
+    let depth_overrides = matches.get_many::<String>("depth_for")
+        .map(|vals| vals.map(|v| DepthOverride::parse(v)).collect::<Result<Vec<_>, _>>())
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+        .unwrap_or_default();
+    let max_entries = matches.get_one::<String>("max_entries")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
     let output_format = matches.get_one::<String>("format").unwrap().to_string();
     let exclude_dirs: Vec<String> = matches.get_many::<String>("exclude")
         .map(|vals| vals.cloned().collect())
         .unwrap_or_default();
     let show_content = matches.get_flag("content");
     let max_file_size = matches.get_one::<String>("max_size")
-        .and_then(|v| v.parse().ok())
+        .map(|v| parse_size(v).unwrap_or_else(|| {
+            eprintln!("Error: invalid size '{}'", v);
+            std::process::exit(1);
+        }))
         .unwrap_or(100000);
+    let size_style = SizeStyle::from_str(matches.get_one::<String>("size_style").unwrap());
     let file_types: Vec<String> = matches.get_many::<String>("type")
         .map(|vals| vals.cloned().collect())
         .unwrap_or_default();
-    let content_filter = matches.get_one::<String>("pattern")
-        .map(|p| Regex::new(p).unwrap());
-    let content_context = matches.get_one::<String>("context")
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
+    let patterns: Vec<String> = matches.get_many::<String>("pattern")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let and_patterns: Vec<String> = matches.get_many::<String>("and_pattern")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let match_options = MatchOptions {
+        ignore_case: matches.get_flag("ignore_case"),
+        smart_case: matches.get_flag("smart_case"),
+        fixed_strings: matches.get_flag("fixed_strings"),
+        word: matches.get_flag("word"),
+        invert: matches.get_flag("invert_match"),
+        multiline: matches.get_flag("multiline"),
+    };
+    let replacement = matches.get_one::<String>("replace").cloned();
+    let match_listing = if matches.get_flag("files_with_matches") {
+        Some(MatchListing::Paths)
+    } else if matches.get_flag("count") {
+        Some(MatchListing::Counts)
+    } else {
+        None
+    };
+    let matching_only = matches.get_flag("matching_only") || match_listing == Some(MatchListing::Paths);
+    if (matching_only || match_listing.is_some()) && patterns.is_empty() && and_patterns.is_empty() {
+        eprintln!("Error: --matching-only, -l and --count need a pattern (-p or --and-pattern)");
+        std::process::exit(1);
+    }
+    let content_filter = if patterns.is_empty() && and_patterns.is_empty() {
+        None
+    } else {
+        Some(Matcher::new(&patterns, &and_patterns, match_options).unwrap_or_else(|e| {
+            eprintln!("Error: invalid pattern: {}", e);
+            std::process::exit(1);
+        }))
+    };
+    let number = |id: &str| matches.get_one::<String>(id).and_then(|v| v.parse::<usize>().ok());
+    let content_context = number("context").unwrap_or(0);
+    let context_before = number("before_context").unwrap_or(content_context);
+    let context_after = number("after_context").unwrap_or(content_context);
+    let max_count = number("max_count").unwrap_or(0);
+    let max_total_matches = number("max_total_matches").unwrap_or(0);
     let whole_file = matches.get_flag("whole_file");
+    let encoding = matches.get_one::<String>("encoding").map(|label| {
+        encoding::parse(label).unwrap_or_else(|| {
+            eprintln!("Error: unknown encoding '{}'", label);
+            std::process::exit(1);
+        })
+    });
     let highlight = matches.get_flag("highlight");
-    let sort_by = SortField::from_str(matches.get_one::<String>("sort").unwrap());
     let sort_direction = SortDirection::from_str(matches.get_one::<String>("direction").unwrap());
-    let sort_dirs_first = if matches.get_flag("no_dirs_first") {
-        false
-    } else if matches.get_flag("dirs_first") {
-        true
-    } else {
-        true
-    };
+    let sort_keys = SortKey::parse_list(matches.get_one::<String>("sort").unwrap(), sort_direction).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let sort_ignore_case = matches.get_flag("sort_ignore_case");
+    // Directories are listed first unless explicitly disabled; `--dirs-first` only restates the default.
+    let sort_dirs_first = !matches.get_flag("no_dirs_first");
+
+    let follow_links = matches.get_flag("follow");
+    let time_style = TimeStyle::parse(matches.get_one::<String>("time_style").unwrap()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let time_field = TimeField::from_str(matches.get_one::<String>("time_field").unwrap());
+    let one_file_system = matches.get_flag("one_file_system");
+    let flat = matches.get_flag("flat");
+    let prune = matches.get_flag("prune");
+    let collapse = matches.get_flag("collapse");
+    let template = matches.get_one::<String>("template")
+        .map(|t| template::Template::parse(t))
+        .or_else(|| matches.get_one::<String>("columns").map(|c| template::Template::from_columns(c)))
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
 
     let groups = file_type_groups();
 
-    let config = Config {
+    Config {
         max_depth,
-        project_dir,
+        depth_overrides,
+        max_entries,
+        roots,
         exclude_dirs,
         output_format,
         show_content,
         max_file_size,
         file_types,
-        sort_by,
-        sort_direction,
+        sort_keys,
+        sort_ignore_case,
         sort_dirs_first,
         content_filter,
-        content_context,
+        match_options,
+        context_before,
+        context_after,
         whole_file,
+        encoding,
+        max_count,
+        max_total_matches,
+        matches_shown: Cell::new(0),
         highlight,
         groups,
-    };
+        selected: None,
+        file_list: None,
+        follow_links,
+        one_file_system,
+        flat,
+        prune,
+        matching_only,
+        replacement,
+        match_listing,
+        match_counts: RefCell::new(HashMap::new()),
+        visible_dirs: RefCell::new(HashMap::new()),
+        collapse,
+        template,
+        user_names: read_id_names("/etc/passwd"),
+        group_names: read_id_names("/etc/group"),
+        time_style,
+        time_field,
+        size_style,
+        git_dates: OnceCell::new(),
+    }
+}
 
-    if !config.project_dir.is_dir() {
-        eprintln!("Error: '{}' is not a directory.", config.project_dir.display());
-        std::process::exit(1);
+/// Renders every root as its own section, each with its own header, followed by a single footer.
+fn render(config: &Config) {
+    // Files may have changed since the last render in --watch mode, and each render gets the full match budget.
+    config.match_counts.borrow_mut().clear();
+    config.visible_dirs.borrow_mut().clear();
+    config.matches_shown.set(0);
+
+    if let Some(listing) = config.match_listing {
+        print_match_listing(config, listing);
+        return;
+    }
+    if config.flat {
+        print_flat(config);
+        return;
+    }
+
+    for root in &config.roots {
+        print_header(root, config);
+        print_tree(root, "", config, 1);
+        println!();
     }
 
-    // Print header
     if config.output_format == "markdown" {
-        println!("# 📁 Project Source Tree: {}", config.project_dir.file_name().unwrap_or_else(|| OsStr::new(".")).to_string_lossy());
+        println!("_End of source tree_");
     } else {
-        println!("Project Source Tree: {}", config.project_dir.file_name().unwrap_or_else(|| OsStr::new(".")).to_string_lossy());
+        println!("End of source tree");
+    }
+}
+
+fn print_header(root: &Path, config: &Config) {
+    if config.output_format == "markdown" {
+        println!("# 📁 Project Source Tree: {}", root.file_name().unwrap_or_else(|| OsStr::new(".")).to_string_lossy());
+    } else {
+        println!("Project Source Tree: {}", root.file_name().unwrap_or_else(|| OsStr::new(".")).to_string_lossy());
     }
     println!("Generated on {}", Utc::now().to_rfc3339());
     if !config.file_types.is_empty() {
@@ -297,18 +1147,62 @@ r#"Type Filters:
     if let Some(ref pat) = config.content_filter {
         println!("Content Pattern: {}", pat);
     }
-    if matches.get_one::<String>("sort").unwrap() != "name" || matches.get_one::<String>("direction").unwrap() != "asc" {
-        println!("Sorting: {:?} ({:?})", config.sort_by, config.sort_direction);
+    if config.sort_keys.iter().any(|key| key.field != SortField::Name || key.direction != SortDirection::Asc) {
+        println!("Sorting: {}", describe_sort(&config.sort_keys));
     }
     println!();
+}
 
-    print_tree(&config.project_dir, "", &config, 1);
+fn check_roots(config: &Config) {
+    for root in &config.roots {
+        if !root.is_dir() {
+            eprintln!("Error: '{}' is not a directory.", root.display());
+            std::process::exit(1);
+        }
+    }
+}
 
-    println!();
-    if config.output_format == "markdown" {
-        println!("_End of source tree_");
-    } else {
-        println!("End of source tree");
+/// How a path is shown outside the tree: relative to the root when there is only one,
+/// otherwise as given (`src/main.rs` for root `src`) so entries from different roots stay apart.
+fn display_path(config: &Config, path: &Path) -> String {
+    match config.roots.as_slice() {
+        [root] => path.strip_prefix(root).unwrap_or(path).display().to_string(),
+        _ => path.display().to_string(),
+    }
+}
+
+impl Config {
+    /// The encoding a text file is read with: the `--encoding` one, or else detected from its contents.
+    fn file_encoding(&self, path: &Path) -> &'static Encoding {
+        encoding::for_file(path, self.encoding)
+    }
+
+    /// Whether the entries of `dir`, which sit at tree level `depth` (the root's own entries being level 1), are listed.
+    ///
+    /// The deepest `--depth-for` rule containing `dir` counts levels from its own path instead of the root, and the
+    /// directories leading to a rule are always opened so that it can be reached.
+    fn within_depth(&self, dir: &Path, depth: usize) -> bool {
+        let mut rule: Option<&DepthOverride> = None;
+        for root in &self.roots {
+            for candidate in &self.depth_overrides {
+                let path = root.join(&candidate.path);
+                if path.starts_with(dir) && path != dir {
+                    return true;
+                }
+                if dir.starts_with(&path) && rule.is_none_or(|r| candidate.path.components().count() > r.path.components().count()) {
+                    rule = Some(candidate);
+                }
+            }
+        }
+        match rule {
+            Some(rule) => rule.depth == 0 || depth.saturating_sub(rule.path.components().count()) <= rule.depth,
+            None => self.max_depth == 0 || depth <= self.max_depth,
+        }
+    }
+
+    /// The first (for `rs tui` and `--files-from`, the only) root directory.
+    fn root(&self) -> &Path {
+        &self.roots[0]
     }
 }
 
@@ -316,9 +1210,264 @@ struct DirEntryExt {
     path: PathBuf,
     is_dir: bool,
     size: u64,
-    modified: Option<SystemTime>,
+    /// The `--time-field` timestamp: what is displayed and what `--sort date` orders by.
+    time: Option<SystemTime>,
     ext: Option<String>,
     filetype_desc: String,
+    link_target: Option<PathBuf>,
+    broken_link: bool,
+    cycle: bool,
+    mount_point: bool,
+    metadata: fs::Metadata,
+}
+
+impl DirEntryExt {
+    fn new(path: PathBuf, metadata: &fs::Metadata, time_field: TimeField) -> Self {
+        let is_dir = metadata.is_dir();
+        let size = if !is_dir { metadata.len() } else { 0 };
+        let time = time_field.of(metadata);
+        let ext = path.extension().map(|e| e.to_string_lossy().to_string());
+        let filetype_desc = file_type_description(&path);
+
+        DirEntryExt {
+            path,
+            is_dir,
+            size,
+            time,
+            ext,
+            filetype_desc,
+            link_target: None,
+            broken_link: false,
+            cycle: false,
+            mount_point: false,
+            metadata: metadata.clone(),
+        }
+    }
+
+    /// Whether the walk enters this directory: not through a cycle, and not across a mount point with `-x`.
+    fn can_descend(&self, config: &Config) -> bool {
+        self.is_dir && !self.cycle && !(self.mount_point && config.one_file_system)
+    }
+
+    /// Whether reading the entry goes through a symbolic link that is listed as itself (no `--follow`): its size is
+    /// then the link's own, and the target may lie outside the tree.
+    fn unfollowed_link(&self, config: &Config) -> bool {
+        self.link_target.is_some() && !config.follow_links
+    }
+
+    /// Whether the file's contents are shown and searched: a text file within `--max-size`, not behind an unfollowed link.
+    fn has_readable_content(&self, config: &Config) -> bool {
+        !self.is_dir && !self.unfollowed_link(config) && self.size <= config.max_file_size && is_text_file(&self.path)
+    }
+
+    /// The file name, followed by `-> target` for symbolic links.
+    fn display_name(&self) -> String {
+        let name = self.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
+        match self.link_target {
+            Some(ref target) => format!("{} -> {}", name, target.display()),
+            None => name.to_string(),
+        }
+    }
+}
+
+/// The entries of `dir` that the listing shows, sorted; with `--prune`, directories with nothing to show below them are dropped.
+fn read_entries(dir: &Path, config: &Config) -> io::Result<Vec<DirEntryExt>> {
+    let mut entries = read_unsorted_entries(dir, config)?;
+    if config.prune || config.matching_only {
+        entries.retain(|entry| !entry.is_dir || keep_pruned_dir(entry, config));
+    }
+    sort_entries(&mut entries, config);
+    Ok(entries)
+}
+
+/// Under `--prune`, a directory stays if it matches the type filters itself or any file below it (at any depth) is shown;
+/// under `--matching-only`, only a file below it counts.
+fn keep_pruned_dir(entry: &DirEntryExt, config: &Config) -> bool {
+    let matches_itself = !config.matching_only
+        && !config.file_types.is_empty()
+        && matches_type_filter(&entry.path, &config.file_types, true, &config.groups, &config.user_names);
+    matches_itself || (entry.can_descend(config) && has_visible_files(&entry.path, config))
+}
+
+fn has_visible_files(dir: &Path, config: &Config) -> bool {
+    if let Some(&visible) = config.visible_dirs.borrow().get(dir) {
+        return visible;
+    }
+    let visible = read_unsorted_entries(dir, config).is_ok_and(|entries| {
+        entries.iter().any(|entry| !entry.is_dir)
+            || entries.iter().any(|entry| entry.can_descend(config) && has_visible_files(&entry.path, config))
+    });
+    config.visible_dirs.borrow_mut().insert(dir.to_path_buf(), visible);
+    visible
+}
+
+/// Reads one directory, dropping excluded names and entries rejected by the type filters, unsorted.
+///
+/// With `--files-from` the children come from the file list instead of the filesystem; directories are then
+/// only there to hold the listed files, so the type filters apply to files alone.
+fn read_unsorted_entries(dir: &Path, config: &Config) -> io::Result<Vec<DirEntryExt>> {
+    let paths: Vec<PathBuf> = match config.file_list {
+        Some(ref list) => list.get(dir).map(|children| children.iter().cloned().collect()).unwrap_or_default(),
+        None => fs::read_dir(dir)?.flatten().map(|entry| entry.path()).collect(),
+    };
+
+    // Directories on another device than the one being read are mount points.
+    let dir_dev = fs::metadata(dir).map(|m| m.dev()).ok();
+
+    let mut entries = Vec::new();
+    for path in paths {
+        let file_name_str = path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
+
+        if config.exclude_dirs.contains(&file_name_str.to_string()) {
+            continue;
+        }
+
+        let link_metadata = match fs::symlink_metadata(&path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let is_link = link_metadata.file_type().is_symlink();
+        let target_metadata = if is_link { fs::metadata(&path).ok() } else { None };
+        // Links are listed as themselves unless --follow is given and the target exists.
+        let metadata = match target_metadata {
+            Some(ref m) if config.follow_links => m,
+            _ => &link_metadata,
+        };
+
+        let is_dir = metadata.is_dir();
+        // Type filters pick files, not the directories leading to them, when the paths are listed up front,
+        // a flat listing needs every matching file in the tree, or --prune decides which directories to keep.
+        let pass_through = is_dir && (config.file_list.is_some() || config.flat || config.prune || config.matching_only);
+        if !pass_through && !matches_type_filter(&path, &config.file_types, is_dir, &config.groups, &config.user_names) {
+            continue;
+        }
+
+        let mut entry = DirEntryExt::new(path, metadata, config.time_field);
+        if is_link {
+            entry.link_target = fs::read_link(&entry.path).ok();
+            entry.broken_link = target_metadata.is_none();
+            entry.cycle = entry.is_dir && links_to_ancestor(&entry.path, metadata);
+        }
+        entry.mount_point = entry.is_dir && dir_dev.is_some_and(|dev| dev != metadata.dev());
+        if config.matching_only && !entry.is_dir && match_count(&entry, config).unwrap_or(0) == 0 {
+            continue;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Whether the directory a followed link resolves to (`target`) is one of the link's own ancestors,
+/// compared by device and inode so that descending into it would never terminate.
+fn links_to_ancestor(link: &Path, target: &fs::Metadata) -> bool {
+    let link = std::path::absolute(link).unwrap_or_else(|_| link.to_path_buf());
+    link.ancestors()
+        .skip(1)
+        .filter_map(|ancestor| fs::metadata(ancestor).ok())
+        .any(|m| m.dev() == target.dev() && m.ino() == target.ino())
+}
+
+/// Recursively collects the entries `print_tree` would list, in display order, down to `max_depth`.
+fn walk_entries(dir: &Path, config: &Config, current_depth: usize, out: &mut Vec<DirEntryExt>) {
+    if !config.within_depth(dir, current_depth) {
+        return;
+    }
+
+    let entries = match read_entries(dir, config) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error reading directory '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries {
+        let path = entry.path.clone();
+        let descend = entry.can_descend(config);
+        out.push(entry);
+        if descend {
+            walk_entries(&path, config, current_depth + 1, out);
+        }
+    }
+}
+
+/// `-l` and `--count`: one `PATH` or `PATH:COUNT` line per text file, in tree order, like `grep -rl` and `grep -rc`.
+fn print_match_listing(config: &Config, listing: MatchListing) {
+    let mut entries = Vec::new();
+    for root in &config.roots {
+        walk_entries(root, config, 1, &mut entries);
+    }
+    for entry in entries {
+        let Some(count) = match_count(&entry, config) else {
+            continue;
+        };
+        match listing {
+            MatchListing::Paths => println!("{}", display_path(config, &entry.path)),
+            MatchListing::Counts => println!("{}:{}", display_path(config, &entry.path), count),
+        }
+    }
+}
+
+/// How many lines of a file the content pattern selects, or `None` for files whose content is never shown
+/// (directories, binaries, files over --max-size) or when there is no pattern. Files failing an `--and-pattern` count 0.
+fn match_count(entry: &DirEntryExt, config: &Config) -> Option<usize> {
+    let matcher = config.content_filter.as_ref()?;
+    if !entry.has_readable_content(config) {
+        return None;
+    }
+    if let Some(&count) = config.match_counts.borrow().get(&entry.path) {
+        return Some(count);
+    }
+
+    let lines = decoded_lines(&entry.path, config.file_encoding(&entry.path)).ok()?.map_while(Result::ok).map(|(line, _)| line);
+    let count = if matcher.is_multiline() {
+        let lines: Vec<String> = lines.collect();
+        if matcher.file_matches(&lines) {
+            matcher.search(&lines).iter().filter(|line| line.is_some()).count()
+        } else {
+            0
+        }
+    } else {
+        let (mut count, mut and_found) = (0, Vec::new());
+        for line in lines {
+            count += usize::from(matcher.is_match(&line));
+            matcher.mark_and_patterns(&line, &mut and_found);
+        }
+        if and_found.iter().all(|&found| found) { count } else { 0 }
+    };
+    config.match_counts.borrow_mut().insert(entry.path.clone(), count);
+    Some(count)
+}
+
+/// `--flat`: every entry `print_tree` would show, one path per line and sorted as a single list.
+///
+/// There is no header or footer, so the output can be piped like `find`; directories end in `/`.
+fn print_flat(config: &Config) {
+    let mut entries = Vec::new();
+    for root in &config.roots {
+        walk_entries(root, config, 1, &mut entries);
+    }
+    sort_entries(&mut entries, config);
+
+    for entry in entries {
+        let path = display_path(config, &entry.path);
+        if entry.is_dir {
+            // Directories are always walked; with type filters they are only listed if they match themselves.
+            // Under --prune, read_entries has already dropped the directories that should not be listed.
+            if config.prune
+                || config.matching_only
+                || config.file_types.is_empty()
+                || matches_type_filter(&entry.path, &config.file_types, true, &config.groups, &config.user_names)
+            {
+                println!("{}/", path);
+            }
+            continue;
+        }
+        match config.template {
+            Some(ref template) if !entry.broken_link => println!("{}", template.render(&entry, config)),
+            _ => println!("{}", path),
+        }
+        print_file_content(&entry, "", config);
+    }
 }
 
 fn print_tree(
@@ -327,217 +1476,483 @@ fn print_tree(
     config: &Config,
     current_depth: usize,
 ) {
-    if config.max_depth != 0 && current_depth > config.max_depth {
+    if !config.within_depth(dir, current_depth) {
         return;
     }
 
-    let mut entries = Vec::new();
-    let read_dir = match fs::read_dir(dir) {
-        Ok(r) => r,
+    let mut entries = match read_entries(dir, config) {
+        Ok(entries) => entries,
         Err(e) => {
             eprintln!("{}Error reading directory '{}': {}", prefix, dir.display(), e);
             return;
         }
     };
+    let overflow = if config.max_entries != 0 && entries.len() > config.max_entries {
+        entries.split_off(config.max_entries)
+    } else {
+        Vec::new()
+    };
 
-    for entry_res in read_dir {
-        if let Ok(entry) = entry_res {
-            let file_name = entry.file_name();
-            let file_name_str = file_name.to_string_lossy();
-
-            if config.exclude_dirs.contains(&file_name_str.to_string()) {
+    for entry in entries {
+        let name = entry.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
+        let link_info = match entry.link_target {
+            Some(ref target) => format!(" -> {}", target.display()),
+            None => "".to_string(),
+        };
+        if entry.is_dir {
+            let dir_prefix = if config.output_format == "markdown" { "📁 **" } else { "[DIR] " };
+            let dir_suffix = if config.output_format == "markdown" { "/**" } else { "/" };
+            if entry.cycle {
+                println!("{}{}{}{}{} [cycle, not followed]", prefix, dir_prefix, name, dir_suffix, link_info);
                 continue;
             }
-
-            let metadata = match entry.metadata() {
-                Ok(m) => m,
-                Err(_) => continue,
+            if entry.mount_point && config.one_file_system {
+                println!("{}{}{}{}{} [mount point, not descended]", prefix, dir_prefix, name, dir_suffix, link_info);
+                continue;
+            }
+            let mount_info = if entry.mount_point { " [mount point]" } else { "" };
+            let (name, dir, depth) = if config.collapse {
+                collapse_chain(&entry, config, current_depth)
+            } else {
+                (name.to_string(), entry.path.clone(), current_depth)
             };
-
-            let is_dir = metadata.is_dir();
-            if !matches_type_filter(&entry.path(), &config.file_types, is_dir, &config.groups) {
+            println!("{}{}{}{}{}{}", prefix, dir_prefix, name, dir_suffix, link_info, mount_info);
+            print_tree(&dir, &format!("{}  ", prefix), config, depth + 1);
+        } else {
+            let file_icon = if config.output_format == "markdown" { "📄 " } else { "[FILE] " };
+            if entry.broken_link {
+                println!("{}{}{}{} [broken link]", prefix, file_icon, name, link_info);
                 continue;
             }
+            match config.template {
+                Some(ref template) => println!("{}{}{}", prefix, file_icon, template.render(&entry, config)),
+                None => println!("{}{}{}{} {}", prefix, file_icon, name, link_info, file_details(&entry, config)),
+            }
 
-            let size = if !is_dir { metadata.len() } else { 0 };
-            let modified = metadata.modified().ok();
-            let ext = entry.path().extension().map(|e| e.to_string_lossy().to_string());
-            let filetype_desc = file_type_description(&entry.path());
-
-            entries.push(DirEntryExt {
-                path: entry.path(),
-                is_dir,
-                size,
-                modified,
-                ext,
-                filetype_desc,
-            });
+            print_file_content(&entry, prefix, config);
         }
     }
 
-    sort_entries(&mut entries, &config.sort_by, &config.sort_direction, config.sort_dirs_first);
+    if !overflow.is_empty() {
+        println!("{}{}", prefix, overflow_summary(&overflow, config));
+    }
+}
 
-    for entry in entries {
-        let name = entry.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
-        if entry.is_dir {
-            let dir_info = if let SortField::Date = config.sort_by {
-                if let Some(m) = entry.modified {
-                    let dt: DateTime<Utc> = m.into();
-                    format!(" (modified: {})", dt.to_rfc3339())
-                } else {
-                    "".to_string()
-                }
-            } else if let SortField::Size = config.sort_by {
-                let count = fs::read_dir(&entry.path).map(|d| d.count()).unwrap_or(0);
-                format!(" ({} items)", count)
-            } else {
-                "".to_string()
-            };
+/// The line standing in for the entries `--max-entries` cut off, e.g.
+/// `… 4,312 more files (18.0 MiB): 4,000 .png, 300 .json, 12 other`.
+fn overflow_summary(rest: &[DirEntryExt], config: &Config) -> String {
+    let total_size: u64 = rest.iter().map(|entry| entry.size).sum();
+    let mut kinds: HashMap<String, usize> = HashMap::new();
+    let mut other = 0;
+    for entry in rest {
+        let kind = match entry.ext {
+            _ if entry.is_dir => "directories".to_string(),
+            Some(ref ext) => format!(".{}", ext.to_lowercase()),
+            None => {
+                other += 1;
+                continue;
+            }
+        };
+        *kinds.entry(kind).or_insert(0) += 1;
+    }
 
-            let dir_prefix = if config.output_format == "markdown" { "📁 **" } else { "[DIR] " };
-            let dir_suffix = if config.output_format == "markdown" { "/**" } else { "/" };
-            println!("{}{}{}{}", prefix, dir_prefix, name, dir_suffix);
-            print_tree(&entry.path, &format!("{}  ", prefix), config, current_depth + 1);
-        } else {
-            let (size, modified) = (format_size(entry.size), format_modified(entry.modified));
-            let ext_info = if let Some(ref ext) = entry.ext {
-                format!(".{}", ext)
-            } else {
-                "".to_string()
-            };
+    // The five most common kinds; files without an extension and the long tail are counted as "other".
+    let mut kinds: Vec<(String, usize)> = kinds.into_iter().collect();
+    kinds.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    other += kinds.iter().skip(5).map(|(_, n)| n).sum::<usize>();
+    let mut parts: Vec<String> = kinds
+        .iter()
+        .take(5)
+        .map(|(kind, n)| match kind.as_str() {
+            "directories" if *n == 1 => "1 directory".to_string(),
+            _ => format!("{} {}", format_count(*n), kind),
+        })
+        .collect();
+    if other > 0 {
+        parts.push(format!("{} other", format_count(other)));
+    }
 
-            let file_icon = if config.output_format == "markdown" { "📄 " } else { "[FILE] " };
-            println!("{}{}{} ({}, {}) [{}]{}",
-                     prefix, file_icon, name, size, modified, entry.filetype_desc, ext_info);
-
-            if config.show_content && entry.size <= config.max_file_size && is_text_file(&entry.path) {
-                println!();
-                if config.output_format == "markdown" {
-                    println!("{}  Content:", prefix);
-                    let lang = guess_language(&entry.path);
-                    println!("{}  ```{}", prefix, lang);
-                } else {
-                    println!("{}  --- Content Start ---", prefix);
-                }
-                filter_and_print_content(&entry.path, &config.content_filter, config.content_context, prefix, config.highlight, config.whole_file);
-                if config.output_format == "markdown" {
-                    println!("{}  ```", prefix);
-                } else {
-                    println!("{}  --- Content End ---", prefix);
-                }
-                println!();
-            } else if config.show_content && entry.size > config.max_file_size {
-                println!("{}  (File not displayed - {})", prefix, size);
-            }
+    let noun = if rest.iter().all(|entry| !entry.is_dir) { "files" } else { "entries" };
+    format!(
+        "… {} more {} ({}): {}",
+        format_count(rest.len()),
+        noun,
+        format_size(total_size, config.size_style),
+        parts.join(", ")
+    )
+}
+
+/// A count with thousands separators: `4,312`.
+fn format_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
         }
+        out.push(c);
     }
+    out
 }
 
-fn filter_and_print_content(
-    path: &Path,
-    pattern: &Option<Regex>,
-    context: usize,
-    prefix: &str,
-    highlight: bool,
-    whole_file: bool,
-) {
-    let file = match fs::File::open(path) {
-        Ok(f) => f,
-        Err(_) => {
-            println!("{}    ! Cannot read file", prefix);
-            return;
+/// `--collapse`: follows `entry` down while each directory shows nothing but a single plain subdirectory,
+/// returning the joined name (`main/java/com`), the last directory and its depth.
+fn collapse_chain(entry: &DirEntryExt, config: &Config, depth: usize) -> (String, PathBuf, usize) {
+    let mut name = entry.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy().to_string();
+    let mut dir = entry.path.clone();
+    let mut depth = depth;
+    while config.within_depth(&dir, depth + 1) {
+        let Ok(mut children) = read_entries(&dir, config) else {
+            break;
+        };
+        // Links and mount points keep their own line so their annotations are not lost.
+        match children.as_slice() {
+            [child] if child.can_descend(config) && child.link_target.is_none() && !child.mount_point => {}
+            _ => break,
         }
+        let child = children.remove(0);
+        name.push('/');
+        name.push_str(&child.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy());
+        dir = child.path;
+        depth += 1;
+    }
+    (name, dir, depth)
+}
+
+/// The content block under a file line, if `--content` is on and the file is selected, small enough and text.
+fn print_file_content(entry: &DirEntryExt, prefix: &str, config: &Config) {
+    let wanted = config.selected.as_ref().is_none_or(|selected| selected.contains(&entry.path));
+    if config.show_content && wanted && entry.has_readable_content(config) {
+        if let Err(e) = write_file_content(&mut io::stdout().lock(), &entry.path, prefix, config) {
+            eprintln!("Error writing content of '{}': {}", entry.path.display(), e);
+        }
+    } else if config.show_content && wanted && !entry.unfollowed_link(config) && entry.size > config.max_file_size {
+        println!("{}  (File not displayed - {})", prefix, format_size(entry.size, config.size_style));
+    }
+}
+
+/// The `(size, time) [mime].ext` part of a file line.
+fn file_details(entry: &DirEntryExt, config: &Config) -> String {
+    let ext_info = if let Some(ref ext) = entry.ext {
+        format!(".{}", ext)
+    } else {
+        "".to_string()
     };
+    format!("({}, {}) [{}]{}", format_size(entry.size, config.size_style), format_time(entry.time, &config.time_style), entry.filetype_desc, ext_info)
+}
+
+/// Writes the fenced content block that follows a file line, indented under `prefix`.
+fn write_file_content(out: &mut dyn Write, path: &Path, prefix: &str, config: &Config) -> io::Result<()> {
+    writeln!(out)?;
+    if config.output_format == "markdown" {
+        writeln!(out, "{}  Content:", prefix)?;
+        let lang = guess_language(path);
+        writeln!(out, "{}  ```{}", prefix, lang)?;
+    } else {
+        writeln!(out, "{}  --- Content Start ---", prefix)?;
+    }
+    filter_and_print_content(out, path, prefix, config)?;
+    if config.output_format == "markdown" {
+        writeln!(out, "{}  ```", prefix)?;
+    } else {
+        writeln!(out, "{}  --- Content End ---", prefix)?;
+    }
+    writeln!(out)
+}
 
-    let reader = io::BufReader::new(file);
-    let lines: Vec<String> = reader.lines().filter_map(Result::ok).collect();
-    let total_lines = lines.len();
+/// The lines of a file without their `\n` or `\r\n`, decoded one at a time. Bytes that are not valid UTF-8 become
+/// U+FFFD instead of ending the file early; the flag says whether the line decoded cleanly.
+struct DecodedLines<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
 
-    println!("{}     ┌ Total lines: {}", prefix, total_lines);
-    println!("{}     │", prefix);
+impl<R: BufRead> DecodedLines<R> {
+    fn new(reader: R) -> Self {
+        DecodedLines { reader, buffer: Vec::new() }
+    }
+}
 
-    match pattern {
-        None => {
-            for (i, line) in lines.iter().enumerate() {
-                format_line(line, i + 1, prefix, None, highlight, false);
+impl<R: BufRead> Iterator for DecodedLines<R> {
+    type Item = io::Result<(String, bool)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer.clear();
+        match self.reader.read_until(b'\n', &mut self.buffer) {
+            Ok(0) => None,
+            Ok(_) => {
+                let mut line = self.buffer.as_slice();
+                line = line.strip_suffix(b"\n").unwrap_or(line);
+                line = line.strip_suffix(b"\r").unwrap_or(line);
+                Some(Ok(match std::str::from_utf8(line) {
+                    Ok(text) => (text.to_string(), true),
+                    Err(_) => (String::from_utf8_lossy(line).into_owned(), false),
+                }))
             }
-            println!("{}     │", prefix);
+            Err(e) => Some(Err(e)),
         }
-        Some(regex) => {
-            let mut matches = Vec::new();
-            for (i, line) in lines.iter().enumerate() {
-                if regex.is_match(line) {
-                    matches.push(i + 1);
+    }
+}
+
+/// The lines of a text file, transcoded to UTF-8 from its encoding.
+fn decoded_lines(path: &Path, encoding: &'static Encoding) -> io::Result<DecodedLines<impl BufRead>> {
+    Ok(DecodedLines::new(encoding::reader(path, encoding)?))
+}
+
+/// Writes a file's numbered lines, or with a content pattern only the matches and their context
+/// (`-B`/`-A`, or everything with `--whole-file`), up to `--max-count` per file and `--max-total-matches` overall.
+///
+/// The file is streamed twice, once to count lines and matches for the header and the limits and once to print,
+/// keeping only the `-B` context in memory. `-U` needs the whole file to match across lines and reads it at once.
+fn filter_and_print_content(out: &mut dyn Write, path: &Path, prefix: &str, config: &Config) -> io::Result<()> {
+    let encoding = config.file_encoding(path);
+    let Ok(lines) = decoded_lines(path, encoding) else {
+        writeln!(out, "{}    ! Cannot read file", prefix)?;
+        return Ok(());
+    };
+
+    // With -U the lines and their matches are kept from the first pass; otherwise only counts are.
+    let matcher = config.content_filter.as_ref();
+    let mut buffered: Option<Vec<MatchedLine>> = None;
+    let mut total_lines = 0;
+    let mut invalid_lines = InvalidLines::default();
+    let mut match_total = 0;
+    let mut and_found = Vec::new();
+    if matcher.is_some_and(Matcher::is_multiline) {
+        let mut all = Vec::new();
+        for line in lines {
+            let (line, valid) = line?;
+            total_lines += 1;
+            if !valid {
+                invalid_lines.note(total_lines);
+            }
+            all.push(line);
+        }
+        let matcher = matcher.unwrap();
+        let found = matcher.search(&all);
+        match_total = found.iter().filter(|spans| spans.is_some()).count();
+        if !matcher.file_matches(&all) {
+            match_total = 0;
+        }
+        buffered = Some(all.into_iter().zip(found).collect());
+    } else {
+        for line in lines {
+            let (line, valid) = line?;
+            total_lines += 1;
+            if !valid {
+                invalid_lines.note(total_lines);
+            }
+            if let Some(matcher) = matcher {
+                if matcher.is_match(&line) {
+                    match_total += 1;
                 }
+                matcher.mark_and_patterns(&line, &mut and_found);
             }
+        }
+        if !and_found.iter().all(|&found| found) {
+            match_total = 0;
+        }
+    }
+
+    if encoding == encoding_rs::UTF_8 {
+        writeln!(out, "{}     ┌ Total lines: {}", prefix, total_lines)?;
+    } else {
+        writeln!(out, "{}     ┌ Total lines: {} ({})", prefix, total_lines, encoding.name())?;
+    }
+    writeln!(out, "{}     │", prefix)?;
 
-            if matches.is_empty() {
-                println!("{}    ! No matches found", prefix);
-                println!("{}     │", prefix);
-                return;
+    let Some(matcher) = matcher else {
+        for (i, line) in decoded_lines(path, encoding)?.enumerate() {
+            format_line(out, &line?.0, i + 1, prefix, None, config.highlight)?;
+        }
+        report_invalid_lines(out, prefix, &invalid_lines)?;
+        return writeln!(out, "{}     │", prefix);
+    };
+
+    if match_total == 0 {
+        writeln!(out, "{}    ! No matches found", prefix)?;
+        report_invalid_lines(out, prefix, &invalid_lines)?;
+        return writeln!(out, "{}     │", prefix);
+    }
+
+    let per_file = if config.max_count != 0 { match_total.min(config.max_count) } else { match_total };
+    let allowed = if config.max_total_matches != 0 {
+        per_file.min(config.max_total_matches.saturating_sub(config.matches_shown.get()))
+    } else {
+        per_file
+    };
+    config.matches_shown.set(config.matches_shown.get() + allowed);
+
+    let lines: Box<dyn Iterator<Item = io::Result<MatchedLine>>> = match buffered {
+        Some(lines) => Box::new(lines.into_iter().map(Ok)),
+        None => Box::new(decoded_lines(path, encoding)?.map(|line| line.map(|(line, _)| {
+            let spans = matcher.line_spans(&line);
+            (line, spans)
+        }))),
+    };
+    print_matches(out, lines, allowed, prefix, config)?;
+
+    let elided = match_total - allowed;
+    if elided > 0 {
+        let reason = if allowed < per_file { "--max-total-matches reached" } else { "--max-count" };
+        let more = if allowed == 0 { "" } else { "more " };
+        let noun = if elided == 1 { "match" } else { "matches" };
+        writeln!(out, "{}     │", prefix)?;
+        writeln!(out, "{}    ! {} {}{} not shown ({})", prefix, elided, more, noun, reason)?;
+    }
+    report_invalid_lines(out, prefix, &invalid_lines)?;
+    writeln!(out, "{}     │", prefix)
+}
+
+/// A line with its highlights if the pattern selects it.
+type MatchedLine = (String, Option<Vec<(usize, usize)>>);
+
+/// Prints the first `allowed` selected lines with their context, holding back at most `-B` lines at a time.
+fn print_matches(
+    out: &mut dyn Write,
+    lines: impl Iterator<Item = io::Result<MatchedLine>>,
+    allowed: usize,
+    prefix: &str,
+    config: &Config,
+) -> io::Result<()> {
+    let mut before: VecDeque<(usize, String)> = VecDeque::with_capacity(config.context_before);
+    let mut shown = 0;
+    let mut after_left = 0;
+    let mut prev_end = 0;
+    for (i, line) in lines.enumerate() {
+        let (line, spans) = line?;
+        let line_num = i + 1;
+        let spans = spans.filter(|_| shown < allowed);
+
+        if config.whole_file {
+            shown += usize::from(spans.is_some());
+            print_match_line(out, &line, line_num, prefix, spans.as_deref(), config)?;
+        } else if spans.is_some() {
+            shown += 1;
+            let start = before.front().map_or(line_num, |(n, _)| *n);
+            if prev_end != 0 && start > prev_end + 1 {
+                print_separator(out, prefix)?;
+            }
+            for (n, context) in before.drain(..) {
+                print_match_line(out, &context, n, prefix, None, config)?;
+            }
+            print_match_line(out, &line, line_num, prefix, spans.as_deref(), config)?;
+            prev_end = line_num;
+            after_left = config.context_after;
+        } else if after_left > 0 {
+            after_left -= 1;
+            print_match_line(out, &line, line_num, prefix, None, config)?;
+            prev_end = line_num;
+        } else if shown == allowed {
+            // Nothing after this can be printed.
+            break;
+        } else if config.context_before > 0 {
+            if before.len() == config.context_before {
+                before.pop_front();
             }
+            before.push_back((line_num, line));
+        }
+    }
+    Ok(())
+}
 
-            if whole_file {
-                for (i, line) in lines.iter().enumerate() {
-                    let is_match = regex.is_match(line);
-                    format_line(line, i + 1, prefix, Some(regex), highlight, is_match);
-                }
+/// The lines of a file that were not valid UTF-8: how many, and the first few line numbers to name.
+#[derive(Default)]
+struct InvalidLines {
+    count: usize,
+    first: Vec<usize>,
+}
+
+impl InvalidLines {
+    const LISTED: usize = 5;
+
+    fn note(&mut self, line_num: usize) {
+        self.count += 1;
+        if self.first.len() < Self::LISTED {
+            self.first.push(line_num);
+        }
+    }
+}
+
+/// Notes the lines that were not valid UTF-8, which are printed with U+FFFD in place of the bad bytes.
+fn report_invalid_lines(out: &mut dyn Write, prefix: &str, invalid_lines: &InvalidLines) -> io::Result<()> {
+    if invalid_lines.count == 0 {
+        return Ok(());
+    }
+    let mut listed: Vec<String> = invalid_lines.first.iter().map(usize::to_string).collect();
+    if invalid_lines.count > listed.len() {
+        listed.push("…".to_string());
+    }
+    let (noun, listed_noun) = if invalid_lines.count == 1 { ("line is", "line") } else { ("lines are", "lines") };
+    writeln!(out, "{}     │", prefix)?;
+    writeln!(
+        out,
+        "{}    ! {} {} not valid UTF-8 ({} {}); invalid bytes are shown as �",
+        prefix,
+        invalid_lines.count,
+        noun,
+        listed_noun,
+        listed.join(", ")
+    )
+}
+
+/// A line of a filtered file; with `--replace`, a matching line becomes a `-` original / `+` replaced pair.
+fn print_match_line(
+    out: &mut dyn Write,
+    line: &str,
+    line_num: usize,
+    prefix: &str,
+    spans: Option<&[(usize, usize)]>,
+    config: &Config,
+) -> io::Result<()> {
+    let replaced = match (spans, &config.replacement, &config.content_filter) {
+        (Some(_), Some(replacement), Some(matcher)) => matcher.replace(line, replacement),
+        _ => None,
+    };
+    match replaced {
+        Some(new_line) if new_line != line => {
+            let (old_line, new_line) = if config.highlight {
+                (line.red().to_string(), new_line.green().to_string())
             } else {
-                let context_num = context;
-                let mut prev_end = 0;
-                for &match_num in &matches {
-                    let start = if match_num > context_num { match_num - context_num } else { 1 };
-                    let end = std::cmp::min(match_num + context_num, total_lines);
-
-                    if start > prev_end + 1 {
-                        print_separator(prefix);
-                    }
-
-                    for i in start..=end {
-                        let line = &lines[i - 1];
-                        let is_match = i == match_num;
-                        format_line(line, i, prefix, Some(regex), highlight, is_match);
-                    }
-
-                    prev_end = end;
-                }
-            }
-            println!("{}     │", prefix);
+                (line.to_string(), new_line)
+            };
+            writeln!(out, "{}{:4} │- {}", prefix, line_num, old_line)?;
+            writeln!(out, "{}     │+ {}", prefix, new_line)
         }
+        _ => format_line(out, line, line_num, prefix, spans, config.highlight),
     }
 }
 
 fn format_line(
+    out: &mut dyn Write,
     line: &str,
     line_num: usize,
     prefix: &str,
-    pattern: Option<&Regex>,
+    spans: Option<&[(usize, usize)]>,
     highlight: bool,
-    is_match: bool
-) {
-    let line_marker = if is_match { "> " } else { "  " };
+) -> io::Result<()> {
+    let line_marker = if spans.is_some() { "> " } else { "  " };
     let line_num_str = format!("{:4} │{}", line_num, line_marker);
 
-    if highlight && pattern.is_some() && is_match {
-        let regex = pattern.unwrap();
+    if let (true, Some(spans)) = (highlight, spans) {
         let mut highlighted_line = String::new();
         let mut last_end = 0;
-        for mat in regex.find_iter(line) {
-            highlighted_line.push_str(&line[last_end..mat.start()]);
-            highlighted_line.push_str(&line[mat.start()..mat.end()].yellow().bold().to_string());
-            last_end = mat.end();
+        for &(start, end) in spans {
+            highlighted_line.push_str(&line[last_end..start]);
+            highlighted_line.push_str(&line[start..end].yellow().bold().to_string());
+            last_end = end;
         }
         highlighted_line.push_str(&line[last_end..]);
-        println!("{}{}{}", prefix, line_num_str, highlighted_line);
+        writeln!(out, "{}{}{}", prefix, line_num_str, highlighted_line)
     } else {
-        println!("{}{}{}", prefix, line_num_str, line);
+        writeln!(out, "{}{}{}", prefix, line_num_str, line)
     }
 }
 
-fn print_separator(prefix: &str) {
-    println!("{}     │", prefix);
-    println!("{}   ⋯ │ ...", prefix);
-    println!("{}     │", prefix);
+fn print_separator(out: &mut dyn Write, prefix: &str) -> io::Result<()> {
+    writeln!(out, "{}     │", prefix)?;
+    writeln!(out, "{}   ⋯ │ ...", prefix)?;
+    writeln!(out, "{}     │", prefix)
 }
 
 fn file_type_description(path: &Path) -> String {
@@ -547,7 +1962,12 @@ fn file_type_description(path: &Path) -> String {
 
 fn is_text_file(path: &Path) -> bool {
     let mime = tree_magic_mini::from_filepath(path).unwrap_or("application/octet-stream");
-    mime.starts_with("text/")
+    is_text_mime(path, mime)
+}
+
+/// Text by MIME type, or, when the type is unknown, text in some encoding, which catches UTF-16 and legacy encodings.
+fn is_text_mime(path: &Path, mime: &str) -> bool {
+    mime.starts_with("text/") || mime == "application/octet-stream" && encoding::detect(path).is_some()
 }
 
 fn guess_language(path: &Path) -> String {
@@ -601,20 +2021,31 @@ fn is_executable_file(path: &Path) -> bool {
     }
 }
 
-fn matches_type_filter(path: &Path, filters: &[String], is_dir: bool, groups: &HashMap<&str, Vec<&str>>) -> bool {
+fn matches_type_filter(
+    path: &Path,
+    filters: &[String],
+    is_dir: bool,
+    groups: &HashMap<&str, Vec<&str>>,
+    user_names: &HashMap<u32, String>,
+) -> bool {
     if filters.is_empty() {
         return true;
     }
 
     let name = path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
-    let metadata = match path.metadata() {
+    // `symlink` has to look at the link itself; everything else describes what it points to, if anything.
+    let link_metadata = match fs::symlink_metadata(path) {
         Ok(m) => m,
         Err(_) => return false,
     };
+    let is_symlink = link_metadata.file_type().is_symlink();
+    let target_metadata = path.metadata().ok();
+    let is_broken = is_symlink && target_metadata.is_none();
+    let metadata = target_metadata.unwrap_or(link_metadata);
     let size = metadata.len();
     let file_type = metadata.file_type();
+    let mode = metadata.mode();
 
-    let is_symlink = file_type.is_symlink();
     let is_socket = file_type.is_socket();
     let is_pipe = file_type.is_fifo();
     let is_block_dev = file_type.is_block_device();
@@ -623,83 +2054,72 @@ fn matches_type_filter(path: &Path, filters: &[String], is_dir: bool, groups: &H
     let is_executable = !is_dir && is_executable_file(path);
 
     let mime = tree_magic_mini::from_filepath(path).unwrap_or("application/octet-stream");
-    let is_text = !is_dir && mime.starts_with("text/");
+    let is_text = !is_dir && is_text_mime(path, mime);
 
     for filter in filters {
-        if filter.starts_with("ext:") {
-            let ext_req = &filter[4..];
+        if let Some(ext_req) = filter.strip_prefix("ext:") {
             let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
             if ext == ext_req {
                 return true;
             }
-        } else if filter.starts_with("group:") {
-            let group = &filter[6..];
+        } else if let Some(group) = filter.strip_prefix("group:") {
             if let Some(exts) = groups.get(group) {
                 let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
                 if exts.contains(&ext) {
                     return true;
                 }
             }
+        } else if let Some(bound) = filter.strip_prefix("size:") {
+            let (op, value) = match bound.find(|c: char| !matches!(c, '<' | '>' | '=')) {
+                Some(i) => bound.split_at(i),
+                None => (bound, ""),
+            };
+            let matched = parse_size(value).is_some_and(|limit| match op {
+                ">" => size > limit,
+                ">=" => size >= limit,
+                "<" => size < limit,
+                "<=" => size <= limit,
+                "" | "=" => size == limit,
+                _ => false,
+            });
+            if !is_dir && matched {
+                return true;
+            }
+        } else if let Some(perm) = filter.strip_prefix("perm:") {
+            let bits = match perm {
+                "world-writable" => Some(0o002),
+                "world-readable" => Some(0o004),
+                "world-executable" => Some(0o001),
+                "group-writable" => Some(0o020),
+                octal => u32::from_str_radix(octal, 8).ok(),
+            };
+            if bits.is_some_and(|bits| mode & bits == bits) {
+                return true;
+            }
+        } else if let Some(owner) = filter.strip_prefix("owner:") {
+            let uid = owner.parse::<u32>().ok()
+                .or_else(|| user_names.iter().find(|(_, name)| *name == owner).map(|(uid, _)| *uid));
+            if uid == Some(metadata.uid()) {
+                return true;
+            }
         } else {
             match filter.as_str() {
-                "binary" => {
-                    if !is_dir && !is_text {
-                        return true;
-                    }
-                }
-                "text" => {
-                    if !is_dir && is_text {
-                        return true;
-                    }
-                }
-                "dir" => {
-                    if is_dir {
-                        return true;
-                    }
-                }
-                "hidden" => {
-                    if name.starts_with('.') {
-                        return true;
-                    }
-                }
-                "empty" => {
-                    if !is_dir && size == 0 {
-                        return true;
-                    }
-                }
-                "all" => {
-                    return true;
-                }
-                "socket" => {
-                    if is_socket {
-                        return true;
-                    }
-                }
-                "pipe" => {
-                    if is_pipe {
-                        return true;
-                    }
-                }
-                "symlink" => {
-                    if is_symlink {
-                        return true;
-                    }
-                }
-                "device" => {
-                    if is_device {
-                        return true;
-                    }
-                }
-                "executable" => {
-                    if is_executable {
-                        return true;
-                    }
-                }
-                "archive" => {
-                    if mime.contains("zip") || mime.contains("x-tar") || mime.contains("x-gzip") {
-                        return true;
-                    }
-                }
+                "binary" if !is_dir && !is_text => return true,
+                "text" if !is_dir && is_text => return true,
+                "dir" if is_dir => return true,
+                "hidden" if name.starts_with('.') => return true,
+                "empty" if !is_dir && size == 0 => return true,
+                "all" => return true,
+                "socket" if is_socket => return true,
+                "pipe" if is_pipe => return true,
+                "symlink" if is_symlink => return true,
+                "broken" if is_broken => return true,
+                "device" if is_device => return true,
+                "executable" if is_executable => return true,
+                "setuid" if mode & 0o4000 != 0 => return true,
+                "setgid" if mode & 0o2000 != 0 => return true,
+                "sticky" if mode & 0o1000 != 0 => return true,
+                "archive" if mime.contains("zip") || mime.contains("x-tar") || mime.contains("x-gzip") => return true,
                 _ => {}
             }
         }
@@ -708,52 +2128,313 @@ fn matches_type_filter(path: &Path, filters: &[String], is_dir: bool, groups: &H
     false
 }
 
-fn sort_entries(entries: &mut [DirEntryExt], sort_by: &SortField, direction: &SortDirection, dirs_first: bool) {
-    entries.sort_by(|a, b| {
-        let dir_cmp = if dirs_first {
-            a.is_dir.cmp(&b.is_dir)
-        } else {
-            std::cmp::Ordering::Equal
-        };
+/// An `ls -l` style mode string such as `-rwsr-xr-x` or `drwxrwxrwt`.
+fn mode_string(metadata: &fs::Metadata) -> String {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else {
+        '-'
+    };
 
-        if dir_cmp != std::cmp::Ordering::Equal {
-            return dir_cmp;
+    let mode = metadata.mode();
+    let bit = |mask: u32, c: char| if mode & mask != 0 { c } else { '-' };
+    // The execute slot doubles as the setuid/setgid/sticky marker: lowercase if also executable, uppercase if not.
+    let special = |exec: u32, special: u32, set: char| match (mode & exec != 0, mode & special != 0) {
+        (true, true) => set,
+        (false, true) => set.to_ascii_uppercase(),
+        (true, false) => 'x',
+        (false, false) => '-',
+    };
+
+    [
+        kind,
+        bit(0o400, 'r'), bit(0o200, 'w'), special(0o100, 0o4000, 's'),
+        bit(0o040, 'r'), bit(0o020, 'w'), special(0o010, 0o2000, 's'),
+        bit(0o004, 'r'), bit(0o002, 'w'), special(0o001, 0o1000, 't'),
+    ]
+    .iter()
+    .collect()
+}
+
+/// Maps numeric ids to names from a passwd- or group-format file (`name:password:id:...`).
+fn read_id_names(path: &str) -> HashMap<u32, String> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+/// Human-readable form of a sort chain for headers and status lines, e.g. `Ext (Asc), Size (Desc)`.
+fn describe_sort(keys: &[SortKey]) -> String {
+    keys.iter()
+        .map(|key| format!("{:?} ({:?})", key.field, key.direction))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Sorts by each key in turn, later keys only breaking ties of earlier ones, then by name so the order is total.
+/// Each key's direction applies to that key alone; directories stay ahead of files either way.
+fn sort_entries(entries: &mut [DirEntryExt], config: &Config) {
+    // A flat listing sorts by the whole path, so the entries of one directory stay together.
+    fn name(entry: &DirEntryExt, flat: bool) -> &OsStr {
+        match entry.path.file_name() {
+            Some(name) if !flat => name,
+            _ => entry.path.as_os_str(),
+        }
+    }
+    let flat = config.flat;
+    let ignore_case = config.sort_ignore_case;
+    let keys = &config.sort_keys;
+
+    // Keys that read files or ask git are computed once per entry rather than on every comparison.
+    let needs = |field: SortField| keys.iter().any(|key| key.field == field);
+    let precompute = |field: SortField, value: &dyn Fn(&DirEntryExt) -> Option<u64>| -> HashMap<PathBuf, Option<u64>> {
+        if !needs(field) {
+            return HashMap::new();
         }
+        entries.iter().map(|entry| (entry.path.clone(), value(entry))).collect()
+    };
+    let lines = precompute(SortField::Lines, &|entry| template::text_only(entry, config, template::count_lines).map(|n| n as u64));
+    let tokens = precompute(SortField::Tokens, &|entry| template::text_only(entry, config, template::estimate_file_tokens).map(|n| n as u64));
+    let git_dates = precompute(SortField::GitDate, &|entry| {
+        let dates = config.git_dates.get_or_init(|| sort::git_commit_dates(&config.roots));
+        let time = dates.get(&sort::canonical_path(&entry.path)?)?;
+        time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+    });
+    let value = |values: &HashMap<PathBuf, Option<u64>>, entry: &DirEntryExt| values.get(&entry.path).copied().flatten();
+
+    let compare = |field: SortField, a: &DirEntryExt, b: &DirEntryExt| match field {
+        SortField::Name => sort::name_cmp(name(a, flat), name(b, flat), ignore_case),
+        SortField::Natural => sort::natural_cmp(name(a, flat), name(b, flat), ignore_case),
+        SortField::Version => sort::version_cmp(name(a, flat), name(b, flat), ignore_case),
+        SortField::Date => a.time.unwrap_or(UNIX_EPOCH).cmp(&b.time.unwrap_or(UNIX_EPOCH)),
+        SortField::Created => TimeField::Birth.of(&a.metadata).cmp(&TimeField::Birth.of(&b.metadata)),
+        SortField::GitDate => value(&git_dates, a).cmp(&value(&git_dates, b)),
+        SortField::Size => a.size.cmp(&b.size),
+        SortField::Lines => value(&lines, a).cmp(&value(&lines, b)),
+        SortField::Tokens => value(&tokens, a).cmp(&value(&tokens, b)),
+        SortField::Type => a.filetype_desc.cmp(&b.filetype_desc),
+        SortField::Ext => a.ext.cmp(&b.ext),
+        SortField::Group => group_rank(a.ext.as_deref()).cmp(&group_rank(b.ext.as_deref())),
+    };
 
-        let cmp = match sort_by {
-            SortField::Name => a.path.file_name().cmp(&b.path.file_name()),
-            SortField::Date => a.modified.unwrap_or(UNIX_EPOCH).cmp(&b.modified.unwrap_or(UNIX_EPOCH)),
-            SortField::Size => a.size.cmp(&b.size),
-            SortField::Type => a.filetype_desc.cmp(&b.filetype_desc),
-            SortField::Ext => a.ext.cmp(&b.ext),
+    entries.sort_by(|a, b| {
+        let dir_cmp = if config.sort_dirs_first && !config.flat {
+            b.is_dir.cmp(&a.is_dir)
+        } else {
+            std::cmp::Ordering::Equal
         };
 
-        cmp
+        keys.iter()
+            .fold(dir_cmp, |ordering, key| {
+                ordering.then_with(|| match key.direction {
+                    SortDirection::Asc => compare(key.field, a, b),
+                    SortDirection::Desc => compare(key.field, b, a),
+                })
+            })
+            .then_with(|| compare(SortField::Name, a, b))
     });
+}
 
-    if let SortDirection::Desc = direction {
-        entries.reverse();
+fn format_size(size: u64, style: SizeStyle) -> String {
+    let (base, units) = match style {
+        SizeStyle::Bytes => return size.to_string(),
+        SizeStyle::Iec => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"]),
+        SizeStyle::Si => (1000.0, ["B", "kB", "MB", "GB", "TB", "PB"]),
+    };
+    if (size as f64) < base {
+        return format!("{} B", size);
     }
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    // Round first so 1023.96 KiB is printed as 1.0 MiB rather than 1024.0 KiB.
+    while (value * 10.0).round() / 10.0 >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
 }
 
-fn format_size(size: u64) -> String {
-    if size >= 1_073_741_824 {
-        format!("{}G", size / 1_073_741_824)
-    } else if size >= 1_048_576 {
-        format!("{}M", size / 1_048_576)
-    } else if size >= 1024 {
-        format!("{}K", size / 1024)
-    } else {
-        format!("{}B", size)
+/// Parses a size as printed by `format_size` in any style (`1.9 MiB`, `2.0 MB`, `1992294`), plus the
+/// short forms `K`, `M`, `G`, `T` (binary). Unit letters are case-insensitive; `kB` and `KB` are both decimal.
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "p" | "pib" => 1 << 50,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "pb" => 1_000_000_000_000_000,
+        _ => return None,
+    };
+    Some((number * multiplier as f64).round() as u64)
+}
+
+fn format_time(m: Option<SystemTime>, style: &TimeStyle) -> String {
+    let Some(time) = m else {
+        return "unknown".to_string();
+    };
+    match style {
+        TimeStyle::Iso => DateTime::<Utc>::from(time).to_rfc3339(),
+        TimeStyle::Local => DateTime::<Local>::from(time).to_rfc3339(),
+        TimeStyle::Date => DateTime::<Local>::from(time).format("%Y-%m-%d").to_string(),
+        TimeStyle::Custom(format) => DateTime::<Local>::from(time).format(format).to_string(),
+        TimeStyle::Relative => format_relative(time, SystemTime::now()),
     }
 }
 
-fn format_modified(m: Option<SystemTime>) -> String {
-    if let Some(time) = m {
-        let dt: DateTime<Utc> = time.into();
-        dt.to_rfc3339()
+/// "just now", "5 minutes ago", "3 days ago", or "in 2 hours" for timestamps in the future.
+fn format_relative(time: SystemTime, now: SystemTime) -> String {
+    let (secs, future) = match now.duration_since(time) {
+        Ok(elapsed) => (elapsed.as_secs(), false),
+        Err(e) => (e.duration().as_secs(), true),
+    };
+    if secs < 60 {
+        return "just now".to_string();
+    }
+
+    let (count, unit) = match secs {
+        s if s < 3_600 => (s / 60, "minute"),
+        s if s < 86_400 => (s / 3_600, "hour"),
+        s if s < 7 * 86_400 => (s / 86_400, "day"),
+        s if s < 30 * 86_400 => (s / (7 * 86_400), "week"),
+        s if s < 365 * 86_400 => (s / (30 * 86_400), "month"),
+        s => (s / (365 * 86_400), "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    if future {
+        format!("in {} {}{}", count, unit, plural)
     } else {
-        "unknown".to_string()
+        format!("{} {}{} ago", count, unit, plural)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `print_matches` shows of a file of lines `line 1`, `line 2`, ... where the `matching` ones select: each
+    /// shown line as its number, marked `>` if highlighted as a match, and `⋯` for a separator.
+    fn shown(args: &[&str], lines: impl Iterator<Item = usize>, matching: &[usize], allowed: usize) -> Vec<String> {
+        let config = build_config(&cli().get_matches_from(std::iter::once("rs").chain(args.iter().copied())));
+        let lines = lines.map(|n| Ok((format!("line {}", n), matching.contains(&n).then(|| vec![(0, 4)]))));
+        let mut out = Vec::new();
+        print_matches(&mut out, lines, allowed, "", &config).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                let (num, rest) = line.split_once(" │")?;
+                match num.trim() {
+                    "" => None,
+                    "⋯" => Some("⋯".to_string()),
+                    num if rest.starts_with('>') => Some(format!("{}>", num)),
+                    num => Some(num.to_string()),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prints_context_around_matches() {
+        assert_eq!(shown(&[], 1..=10, &[3, 8], usize::MAX), ["3>", "⋯", "8>"]);
+        assert_eq!(shown(&["-B", "1", "-A", "1"], 1..=10, &[3, 8], usize::MAX), ["2", "3>", "4", "⋯", "7", "8>", "9"]);
+        assert_eq!(shown(&["--context", "2"], 1..=10, &[1, 10], usize::MAX), ["1>", "2", "3", "⋯", "8", "9", "10>"]);
+    }
+
+    #[test]
+    fn joins_touching_context_without_a_separator() {
+        assert_eq!(shown(&["--context", "1"], 1..=10, &[3, 5], usize::MAX), ["2", "3>", "4", "5>", "6"]);
+        assert_eq!(shown(&["--context", "1"], 1..=10, &[3, 6], usize::MAX), ["2", "3>", "4", "5", "6>", "7"]);
+        assert_eq!(shown(&["--context", "1"], 1..=10, &[3, 7], usize::MAX), ["2", "3>", "4", "⋯", "6", "7>", "8"]);
+    }
+
+    #[test]
+    fn keeps_only_the_last_lines_before_a_match() {
+        assert_eq!(shown(&["-B", "2"], 1..=10, &[7], usize::MAX), ["5", "6", "7>"]);
+        // Lines already shown as after-context are not repeated as before-context.
+        assert_eq!(shown(&["-B", "3", "-A", "2"], 1..=10, &[2, 6], usize::MAX), ["1", "2>", "3", "4", "5", "6>", "7", "8"]);
+    }
+
+    #[test]
+    fn stops_after_the_allowed_matches() {
+        assert_eq!(shown(&["-A", "1"], 1..=10, &[2, 5, 8], 2), ["2>", "3", "⋯", "5>", "6"]);
+        // Later matches inside the last context are shown as plain context.
+        assert_eq!(shown(&["-A", "2"], 1..=10, &[2, 3], 1), ["2>", "3", "4"]);
+        // Reading stops once nothing more can be shown.
+        assert_eq!(shown(&["-A", "1"], 1.., &[2], 1), ["2>", "3"]);
+        assert!(shown(&[], 1.., &[], 0).is_empty());
+    }
+
+    #[test]
+    fn crlf_lines_match_like_lf_lines() {
+        let lines: Vec<String> = DecodedLines::new(&b"foo\r\nbar\r\n\r\nbaz"[..]).map(|line| line.unwrap().0).collect();
+        assert_eq!(lines, ["foo", "bar", "", "baz"]);
+        let options = MatchOptions { multiline: true, ..MatchOptions::default() };
+        let matcher = Matcher::new(&[r"o$\n^b".to_string()], &[], options).unwrap();
+        assert_eq!(matcher.search(&lines), [Some(vec![(2, 3)]), Some(vec![(0, 1)]), None, None]);
+    }
+
+    #[test]
+    fn whole_file_marks_only_the_allowed_matches() {
+        assert_eq!(shown(&["--whole-file"], 1..=5, &[2, 4], 1), ["1", "2>", "3", "4", "5"]);
+        assert_eq!(shown(&["--whole-file"], 1..=5, &[2, 4], usize::MAX), ["1", "2>", "3", "4>", "5"]);
+    }
+
+    #[test]
+    fn printed_sizes_parse_back() {
+        let sizes = [0, 1, 999, 1000, 1023, 1024, 1536, 999_949, 1_048_575, 123_456_789, 1 << 40, (1 << 50) + 1, u64::MAX];
+        for size in sizes {
+            assert_eq!(parse_size(&format_size(size, SizeStyle::Bytes)), Some(size));
+            for style in [SizeStyle::Iec, SizeStyle::Si] {
+                let printed = format_size(size, style);
+                let parsed = parse_size(&printed).unwrap_or_else(|| panic!("'{}' does not parse", printed));
+                // One decimal place of at least 1.0 is within 5%, and the parsed size prints the same.
+                assert!((parsed as f64 - size as f64).abs() <= size as f64 * 0.05, "{} -> '{}' -> {}", size, printed, parsed);
+                assert_eq!(format_size(parsed, style), printed);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_units_in_any_case() {
+        assert_eq!(parse_size("10"), Some(10));
+        assert_eq!(parse_size("1.5 KiB"), Some(1536));
+        assert_eq!(parse_size("1.5k"), Some(1536));
+        assert_eq!(parse_size("10kB"), Some(10_000));
+        assert_eq!(parse_size("2 MB"), Some(2_000_000));
+        assert_eq!(parse_size("1PiB"), Some(1 << 50));
+        assert_eq!(parse_size("1 pb"), Some(1_000_000_000_000_000));
+        assert_eq!(parse_size("1 xb"), None);
+        assert_eq!(parse_size("MiB"), None);
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+
+use regex::Regex;
+
+/// How `-p` and `--and-pattern` patterns are interpreted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatchOptions {
+    pub ignore_case: bool,
+    /// Ignore case unless the pattern contains an uppercase letter (overridden by `ignore_case`).
+    pub smart_case: bool,
+    pub fixed_strings: bool,
+    pub word: bool,
+    pub invert: bool,
+    /// Search the whole file rather than line by line, so a match may span lines.
+    pub multiline: bool,
+}
+
+/// The content filter: lines matching any `-p` pattern (or, with `-v`, none of them) are shown, in files that also
+/// contain every `--and-pattern` somewhere.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    patterns: Vec<String>,
+    and_patterns: Vec<String>,
+    options: MatchOptions,
+    /// All `-p` patterns as one alternation; `None` if only `--and-pattern`s were given.
+    any: Option<Regex>,
+    all: Vec<Regex>,
+}
+
+impl Matcher {
+    pub fn new(patterns: &[String], and_patterns: &[String], options: MatchOptions) -> Result<Self, regex::Error> {
+        let any = if patterns.is_empty() {
+            None
+        } else {
+            let sources: Vec<String> = patterns.iter().map(|p| pattern_source(p, options)).collect();
+            Some(Regex::new(&sources.join("|"))?)
+        };
+        let all = and_patterns.iter().map(|p| Regex::new(&pattern_source(p, options))).collect::<Result<_, _>>()?;
+        Ok(Matcher { patterns: patterns.to_vec(), and_patterns: and_patterns.to_vec(), options, any, all })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    fn line_hits(&self, line: &str) -> bool {
+        match self.any {
+            Some(ref regex) => regex.is_match(line),
+            None => self.all.iter().any(|regex| regex.is_match(line)),
+        }
+    }
+
+    /// Whether a single line (or a file name) is selected, taking `-v` into account.
+    pub fn is_match(&self, line: &str) -> bool {
+        self.line_hits(line) != self.options.invert
+    }
+
+    /// Highlights for a selected line, or `None` if the line is not selected; `search` for a single line.
+    pub fn line_spans(&self, line: &str) -> Option<Vec<(usize, usize)>> {
+        self.is_match(line).then(|| self.spans(line))
+    }
+
+    /// Records which `--and-pattern`s occur in `line`, for checking a file line by line; `found` starts out empty.
+    /// The file qualifies once every entry is `true`.
+    pub fn mark_and_patterns(&self, line: &str, found: &mut Vec<bool>) {
+        found.resize(self.all.len(), false);
+        for (regex, found) in self.all.iter().zip(found.iter_mut()) {
+            *found = *found || regex.is_match(line);
+        }
+    }
+
+    pub fn is_multiline(&self) -> bool {
+        self.options.multiline
+    }
+
+    /// Whether a file passes the `--and-pattern` requirement: every one matches a line, or with `-U` anywhere in the file.
+    pub fn file_matches(&self, lines: &[String]) -> bool {
+        if self.options.multiline {
+            let text = lines.join("\n");
+            self.all.iter().all(|regex| regex.is_match(&text))
+        } else {
+            self.all.iter().all(|regex| lines.iter().any(|line| regex.is_match(line)))
+        }
+    }
+
+    /// For each line, `None` if it is not selected, or the byte ranges to highlight in it.
+    ///
+    /// Highlights cover every `-p` and `--and-pattern` match, merged; inverted matches have none. With `-U` the
+    /// file is searched as one buffer and each match selects every line it touches.
+    pub fn search(&self, lines: &[String]) -> Vec<Option<Vec<(usize, usize)>>> {
+        if !self.options.multiline {
+            return lines.iter().map(|line| self.line_spans(line)).collect();
+        }
+
+        let text = lines.join("\n");
+        let mut starts = Vec::with_capacity(lines.len());
+        let mut offset = 0;
+        for line in lines {
+            starts.push(offset);
+            offset += line.len() + 1;
+        }
+        // The line containing byte `pos` of the joined text.
+        let line_of = |pos: usize| starts.partition_point(|&start| start <= pos).saturating_sub(1);
+
+        let mut hit = vec![false; lines.len()];
+        let mut spans: Vec<Vec<(usize, usize)>> = vec![Vec::new(); lines.len()];
+        for (index, regex) in self.any.iter().chain(&self.all).enumerate() {
+            // The `-p` patterns select lines and `--and-pattern`s only add highlights, unless there is no `-p`.
+            let selects = self.any.is_none() || index == 0;
+            for m in regex.find_iter(&text) {
+                let last = line_of(m.end().saturating_sub(1).max(m.start()));
+                for line in line_of(m.start())..=last {
+                    if selects {
+                        hit[line] = true;
+                    }
+                    let (line_start, line_end) = (starts[line], starts[line] + lines[line].len());
+                    let (start, end) = (m.start().max(line_start), m.end().min(line_end));
+                    if start < end {
+                        spans[line].push((start - line_start, end - line_start));
+                    }
+                }
+            }
+        }
+
+        hit.into_iter()
+            .zip(spans)
+            .map(|(hit, spans)| match (hit, self.options.invert) {
+                (true, false) => Some(merge_spans(spans)),
+                (false, true) => Some(Vec::new()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The line with every `-p` match replaced by `replacement` (`$1`, `${name}` and `$$` as in the regex crate),
+    /// or `None` if nothing in it matches.
+    pub fn replace(&self, line: &str, replacement: &str) -> Option<String> {
+        let regex = self.any.as_ref()?;
+        regex.is_match(line).then(|| regex.replace_all(line, replacement).into_owned())
+    }
+
+    fn spans(&self, line: &str) -> Vec<(usize, usize)> {
+        if self.options.invert {
+            return Vec::new();
+        }
+        let spans = self
+            .any
+            .iter()
+            .chain(&self.all)
+            .flat_map(|regex| regex.find_iter(line).map(|m| (m.start(), m.end())))
+            .filter(|(start, end)| start < end)
+            .collect();
+        merge_spans(spans)
+    }
+}
+
+fn merge_spans(mut spans: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    spans.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.patterns.join(" | "))?;
+        if !self.and_patterns.is_empty() {
+            if !self.patterns.is_empty() {
+                write!(f, " ")?;
+            }
+            write!(f, "(and {})", self.and_patterns.join(", "))?;
+        }
+        let flags: Vec<&str> = [
+            (self.options.ignore_case, "ignore case"),
+            (self.options.smart_case && !self.options.ignore_case, "smart case"),
+            (self.options.fixed_strings, "fixed strings"),
+            (self.options.word, "whole words"),
+            (self.options.invert, "inverted"),
+            (self.options.multiline, "multiline"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect();
+        if !flags.is_empty() {
+            write!(f, " [{}]", flags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// One pattern as a self-contained regex group, so case and word options apply to it alone when patterns are joined.
+fn pattern_source(pattern: &str, options: MatchOptions) -> String {
+    let ignore_case = options.ignore_case || (options.smart_case && !pattern.chars().any(char::is_uppercase));
+    let body = if options.fixed_strings { regex::escape(pattern) } else { pattern.to_string() };
+    // Across lines, `^` and `$` still mean the start and end of a line.
+    let flags = match (ignore_case, options.multiline) {
+        (true, true) => "?im",
+        (true, false) => "?i",
+        (false, true) => "?m",
+        (false, false) => "?",
+    };
+    let group = format!("({}:{})", flags, body);
+    if options.word {
+        format!(r"\b{}\b", group)
+    } else {
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split('\n').map(str::to_string).collect()
+    }
+
+    fn matcher(patterns: &[&str], and_patterns: &[&str], options: MatchOptions) -> Matcher {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        Matcher::new(&strings(patterns), &strings(and_patterns), options).unwrap()
+    }
+
+    const MULTILINE: MatchOptions = MatchOptions {
+        ignore_case: false,
+        smart_case: false,
+        fixed_strings: false,
+        word: false,
+        invert: false,
+        multiline: true,
+    };
+
+    #[test]
+    fn searches_line_by_line() {
+        let search = matcher(&["o+"], &[], MatchOptions::default()).search(&lines("foo\nbar\nboo oo"));
+        assert_eq!(search, [Some(vec![(1, 3)]), None, Some(vec![(1, 3), (4, 6)])]);
+    }
+
+    #[test]
+    fn multiline_match_selects_every_line_it_touches() {
+        let text = lines("one\ntwo start\nmiddle\nend three\nfour");
+        let search = matcher(&[r"start\n.*\nend"], &[], MULTILINE).search(&text);
+        assert_eq!(search, [None, Some(vec![(4, 9)]), Some(vec![(0, 6)]), Some(vec![(0, 3)]), None]);
+    }
+
+    #[test]
+    fn multiline_maps_offsets_to_lines() {
+        // Matches at the very start and end of the text, on an empty line, and on a newline alone.
+        let text = lines("ab\n\ncd\nef");
+        assert_eq!(matcher(&["^ab"], &[], MULTILINE).search(&text), [Some(vec![(0, 2)]), None, None, None]);
+        assert_eq!(matcher(&["ef$"], &[], MULTILINE).search(&text), [None, None, None, Some(vec![(0, 2)])]);
+        assert_eq!(matcher(&["^$"], &[], MULTILINE).search(&text), [None, Some(vec![]), None, None]);
+        assert_eq!(matcher(&[r"d\n"], &[], MULTILINE).search(&text), [None, None, Some(vec![(1, 2)]), None]);
+        assert_eq!(matcher(&[r"\n\n"], &[], MULTILINE).search(&text), [Some(vec![]), Some(vec![]), None, None]);
+    }
+
+    #[test]
+    fn multiline_offsets_count_bytes_of_wide_characters() {
+        let text = lines("héllo\nwörld");
+        assert_eq!(matcher(&["ö"], &[], MULTILINE).search(&text), [None, Some(vec![(1, 3)])]);
+        assert_eq!(matcher(&[r"o\nw"], &[], MULTILINE).search(&text), [Some(vec![(5, 6)]), Some(vec![(0, 1)])]);
+    }
+
+    #[test]
+    fn multiline_and_patterns_only_highlight() {
+        let text = lines("foo\nbar\nbaz");
+        let search = matcher(&["foo"], &["ba."], MULTILINE).search(&text);
+        assert_eq!(search, [Some(vec![(0, 3)]), None, None]);
+        let search = matcher(&[], &["ba."], MULTILINE).search(&text);
+        assert_eq!(search, [None, Some(vec![(0, 3)]), Some(vec![(0, 3)])]);
+    }
+
+    #[test]
+    fn inverted_search_selects_the_other_lines_without_highlights() {
+        let text = lines("foo\nbar\nfoo bar");
+        let inverted = MatchOptions { invert: true, ..MatchOptions::default() };
+        assert_eq!(matcher(&["foo"], &[], inverted).search(&text), [None, Some(vec![]), None]);
+        let inverted = MatchOptions { invert: true, ..MULTILINE };
+        assert_eq!(matcher(&[r"foo\nbar"], &[], inverted).search(&text), [None, None, Some(vec![])]);
+    }
+
+    #[test]
+    fn merges_overlapping_highlights() {
+        let search = matcher(&["abc"], &["cde", "e"], MatchOptions::default()).search(&lines("abcde xe"));
+        assert_eq!(search, [Some(vec![(0, 5), (7, 8)])]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::{display_path, encoding, walk_entries, Config};
+
+/// `--apply`: rewrites every file in the listing whose content the pattern selects, replacing matches on each
+/// matching line exactly as the `--replace` preview shows them. Display limits such as `--max-count` do not apply.
+///
+/// Line endings and a missing final newline are preserved. Files in other encodings, or that are not valid UTF-8, are skipped
+/// with a warning, as are files that cannot be written; the rest are still processed. Symbolic links are only written
+/// through with `--follow`, and a file reached by several paths is rewritten once.
+pub fn apply(config: &Config, backup: bool) {
+    let (Some(matcher), Some(replacement)) = (&config.content_filter, &config.replacement) else {
+        return;
+    };
+
+    let mut entries = Vec::new();
+    for root in &config.roots {
+        walk_entries(root, config, 1, &mut entries);
+    }
+
+    let (mut files, mut lines) = (0, 0);
+    let mut seen = HashSet::new();
+    for entry in entries {
+        // Without --follow a link is listed as the link, not the file, and may point outside the tree.
+        if !entry.has_readable_content(config) {
+            continue;
+        }
+        if !seen.insert((entry.metadata.dev(), entry.metadata.ino())) {
+            continue;
+        }
+        if config.selected.as_ref().is_some_and(|selected| !selected.contains(&entry.path)) {
+            continue;
+        }
+        let encoding = config.file_encoding(&entry.path);
+        if encoding != encoding_rs::UTF_8 {
+            // Only worth a warning if there was something to replace.
+            let text = encoding::read_to_string(&entry.path, encoding).unwrap_or_default();
+            if text.lines().any(|line| matcher.replace(line, replacement).is_some()) {
+                eprintln!("Skipping '{}': only UTF-8 files are rewritten, not {}", entry.path.display(), encoding.name());
+            }
+            continue;
+        }
+        let text = match fs::read_to_string(&entry.path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("Skipping '{}': {}", entry.path.display(), e);
+                continue;
+            }
+        };
+        let plain_lines: Vec<String> = text.lines().map(str::to_string).collect();
+        if !matcher.file_matches(&plain_lines) {
+            continue;
+        }
+
+        let mut changed = 0;
+        let mut output = String::with_capacity(text.len());
+        for chunk in text.split_inclusive('\n') {
+            let body = chunk.trim_end_matches('\n').trim_end_matches('\r');
+            match matcher.replace(body, replacement) {
+                Some(new_body) if new_body != body => {
+                    changed += 1;
+                    output.push_str(&new_body);
+                    output.push_str(&chunk[body.len()..]);
+                }
+                _ => output.push_str(chunk),
+            }
+        }
+        if changed == 0 {
+            continue;
+        }
+
+        if let Err(e) = rewrite(&entry.path, &output, backup) {
+            eprintln!("Skipping '{}': {}", entry.path.display(), e);
+            continue;
+        }
+        eprintln!("Updated {} ({} {})", display_path(config, &entry.path), changed, if changed == 1 { "line" } else { "lines" });
+        files += 1;
+        lines += changed;
+    }
+    eprintln!("Applied replacements to {} line(s) in {} file(s)", lines, files);
+}
+
+/// Replaces the contents of the file at `path`. With `backup` the original is first copied to FILE.bak, which must
+/// not exist yet.
+///
+/// A temporary file with the original's owner and mode is renamed over it, so an error or interrupt leaves either the
+/// old or the new contents and never a truncated file. A file with other hard links, or one whose owner cannot be given
+/// to a new file, is overwritten in place instead so that it stays the same file.
+fn rewrite(path: &Path, contents: &str, backup: bool) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    if backup {
+        let backup = with_suffix(path, ".bak");
+        if fs::symlink_metadata(&backup).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("backup '{}' already exists", backup.display())));
+        }
+        fs::copy(path, &backup)?;
+    }
+
+    // A followed link is written through to its target rather than replaced by a regular file.
+    let target = fs::canonicalize(path)?;
+    if metadata.nlink() > 1 {
+        return write_in_place(&target, contents);
+    }
+    // Hidden and in the same directory, so the rename stays on one file system.
+    let mut name = OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    let temp = with_suffix(&target.with_file_name(name), &format!(".{}.tmp", std::process::id()));
+    let result = (|| {
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        let created = file.metadata()?;
+        // Only root can give a new file to another owner.
+        if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid())
+            && std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())).is_err()
+        {
+            return Ok(false);
+        }
+        file.write_all(contents.as_bytes())?;
+        file.set_permissions(metadata.permissions())?;
+        file.sync_all()?;
+        fs::rename(&temp, &target).map(|()| true)
+    })();
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(&temp);
+    }
+    match result? {
+        true => Ok(()),
+        false => write_in_place(&target, contents),
+    }
+}
+
+/// Overwrites a file's contents while keeping its inode, and with it its hard links, owner and extended attributes.
+fn write_in_place(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.set_len(contents.len() as u64)?;
+    file.sync_all()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    name.into()
+}
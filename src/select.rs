@@ -0,0 +1,251 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::tui::preview_lines;
+use crate::{display_path, walk_entries, Config, DirEntryExt};
+
+struct Picker<'a> {
+    config: &'a Config,
+    items: Vec<(DirEntryExt, String)>,
+    query: String,
+    visible: Vec<usize>,
+    chosen: BTreeSet<usize>,
+    list_state: ListState,
+    preview_for: Option<usize>,
+    preview: Vec<String>,
+    preview_scroll: u16,
+}
+
+/// Lets the user fuzzy-find and mark files among everything `print_tree` would list.
+///
+/// The picker is drawn on `/dev/tty` so stdout can still be redirected to a file. Returns the chosen
+/// paths in tree order, or `None` if the user cancelled.
+pub fn run(config: &Config) -> io::Result<Option<Vec<PathBuf>>> {
+    let mut entries = Vec::new();
+    for root in &config.roots {
+        walk_entries(root, config, 1, &mut entries);
+    }
+    let items: Vec<(DirEntryExt, String)> = entries
+        .into_iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| {
+            let rel = display_path(config, &entry.path);
+            (entry, rel)
+        })
+        .collect();
+
+    let mut picker = Picker {
+        config,
+        visible: (0..items.len()).collect(),
+        items,
+        query: String::new(),
+        chosen: BTreeSet::new(),
+        list_state: ListState::default(),
+        preview_for: None,
+        preview: Vec::new(),
+        preview_scroll: 0,
+    };
+    picker.list_state.select(if picker.visible.is_empty() { None } else { Some(0) });
+
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(tty))?;
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+    let result = picker.event_loop(&mut terminal);
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    Ok(result?.map(|chosen| chosen.into_iter().map(|i| picker.items[i].0.path.clone()).collect()))
+}
+
+impl Picker<'_> {
+    fn event_loop(&mut self, terminal: &mut Terminal<CrosstermBackend<File>>) -> io::Result<Option<BTreeSet<usize>>> {
+        loop {
+            self.refresh_preview();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+            match key.code {
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Char('c') if ctrl => return Ok(None),
+                KeyCode::Enter => {
+                    if self.chosen.is_empty() {
+                        if let Some(&current) = self.current() {
+                            self.chosen.insert(current);
+                        }
+                    }
+                    return Ok(Some(std::mem::take(&mut self.chosen)));
+                }
+                KeyCode::Up => self.move_selection(-1),
+                KeyCode::Char('p') if ctrl => self.move_selection(-1),
+                KeyCode::Down => self.move_selection(1),
+                KeyCode::Char('n') if ctrl => self.move_selection(1),
+                KeyCode::Tab => {
+                    self.toggle_current();
+                    self.move_selection(1);
+                }
+                KeyCode::BackTab => {
+                    self.toggle_current();
+                    self.move_selection(-1);
+                }
+                KeyCode::Char('a') if ctrl => self.toggle_all_visible(),
+                KeyCode::PageDown => self.preview_scroll = self.preview_scroll.saturating_add(10),
+                KeyCode::PageUp => self.preview_scroll = self.preview_scroll.saturating_sub(10),
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.update_matches();
+                }
+                KeyCode::Char(c) if !ctrl => {
+                    self.query.push(c);
+                    self.update_matches();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn current(&self) -> Option<&usize> {
+        self.list_state.selected().and_then(|i| self.visible.get(i))
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        if self.visible.is_empty() {
+            return;
+        }
+        let current = self.list_state.selected().unwrap_or(0);
+        let next = current.saturating_add_signed(delta).min(self.visible.len() - 1);
+        self.list_state.select(Some(next));
+    }
+
+    fn toggle_current(&mut self) {
+        if let Some(&current) = self.current() {
+            if !self.chosen.remove(&current) {
+                self.chosen.insert(current);
+            }
+        }
+    }
+
+    /// Marks every visible item, or unmarks them all if they are already marked.
+    fn toggle_all_visible(&mut self) {
+        if self.visible.iter().all(|i| self.chosen.contains(i)) {
+            for i in &self.visible {
+                self.chosen.remove(i);
+            }
+        } else {
+            self.chosen.extend(self.visible.iter().copied());
+        }
+    }
+
+    fn update_matches(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, rel))| fuzzy_score(&self.query, rel).map(|score| (score, i)))
+            .collect();
+        // Best match first; ties keep tree order.
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        self.visible = scored.into_iter().map(|(_, i)| i).collect();
+        self.list_state.select(if self.visible.is_empty() { None } else { Some(0) });
+    }
+
+    fn refresh_preview(&mut self) {
+        let current = self.current().copied();
+        if current == self.preview_for {
+            return;
+        }
+        self.preview = current.map(|i| preview_lines(&self.items[i].0, self.config)).unwrap_or_default();
+        self.preview_for = current;
+        self.preview_scroll = 0;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let outer = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.area());
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(45), Constraint::Percentage(55)])
+            .split(outer[0]);
+
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|&i| {
+                let chosen = self.chosen.contains(&i);
+                let mark = if chosen { "● " } else { "  " };
+                let style = if chosen { Style::default().fg(Color::Green) } else { Style::default() };
+                ListItem::new(format!("{}{}", mark, self.items[i].1)).style(style)
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" > {} ", self.query)))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, panes[0], &mut self.list_state);
+
+        let title = self.preview_for.map(|i| format!(" {} ", self.items[i].1)).unwrap_or_default();
+        let preview = Paragraph::new(self.preview.iter().map(|l| Line::raw(l.as_str())).collect::<Vec<_>>())
+            .block(Block::bordered().title(title))
+            .scroll((self.preview_scroll, 0));
+        frame.render_widget(preview, panes[1]);
+
+        let status = format!(
+            "{}/{}  {} selected  Tab mark  Ctrl-A mark all  Enter accept  Esc cancel",
+            self.visible.len(),
+            self.items.len(),
+            self.chosen.len()
+        );
+        frame.render_widget(Paragraph::new(status).style(Style::default().add_modifier(Modifier::DIM)), outer[1]);
+    }
+}
+
+/// Scores `candidate` against a fuzzy `query` (its characters in order, case-insensitively), or `None` if it does not match.
+///
+/// Consecutive characters and characters at the start of a path component or word score higher;
+/// gaps and long candidates cost a little, so `mainrs` prefers `src/main.rs` over `src/domain/res.rs`.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut score = 0i64;
+    let mut pos = 0;
+    let mut prev: Option<usize> = None;
+    for qc in query.chars().flat_map(char::to_lowercase) {
+        let found = (pos..chars.len()).find(|&i| chars[i].to_lowercase().eq(std::iter::once(qc)))?;
+        score += 1;
+        match prev {
+            Some(p) if p + 1 == found => score += 5,
+            Some(p) => score -= (found - p - 1).min(5) as i64,
+            None => {}
+        }
+        if found == 0 || matches!(chars[found - 1], '/' | '_' | '-' | '.' | ' ') {
+            score += 8;
+        }
+        prev = Some(found);
+        pos = found + 1;
+    }
+    Some(score * 10 - chars.len() as i64)
+}
@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Picks up the collation order from `LC_ALL` / `LC_COLLATE` / `LANG`, as `ls` does.
+/// Without it (or under `LANG=C`) names compare byte by byte.
+pub fn init_locale() {
+    // SAFETY: called once from main before any other thread exists; the argument is a valid C string.
+    unsafe {
+        libc::setlocale(libc::LC_COLLATE, c"".as_ptr());
+    }
+}
+
+/// Compares two names with `strcoll`, so accented and other non-ASCII names sort where the locale puts them.
+pub fn collate(a: &[u8], b: &[u8]) -> Ordering {
+    let (Ok(ca), Ok(cb)) = (CString::new(a), CString::new(b)) else {
+        return a.cmp(b);
+    };
+    // SAFETY: both pointers are NUL-terminated strings that outlive the call.
+    let result = unsafe { libc::strcoll(ca.as_ptr(), cb.as_ptr()) };
+    // Locales that consider two distinct names equal still need a stable order.
+    result.cmp(&0).then_with(|| a.cmp(b))
+}
+
+/// Lowercases a name for case-insensitive comparison; non-UTF-8 names only fold ASCII.
+fn fold_case(name: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(name) {
+        Ok(s) => s.to_lowercase().into_bytes(),
+        Err(_) => name.to_ascii_lowercase(),
+    }
+}
+
+/// Applies `cmp` to the names, case-folded if asked, falling back to the exact names to break ties.
+fn compare_with(a: &OsStr, b: &OsStr, ignore_case: bool, cmp: fn(&[u8], &[u8]) -> Ordering) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if ignore_case {
+        cmp(&fold_case(a), &fold_case(b)).then_with(|| cmp(a, b))
+    } else {
+        cmp(a, b)
+    }
+}
+
+/// `--sort name`: the locale's collation order.
+pub fn name_cmp(a: &OsStr, b: &OsStr, ignore_case: bool) -> Ordering {
+    compare_with(a, b, ignore_case, collate)
+}
+
+/// `--sort natural`: runs of digits compare as numbers (`file2` before `file10`), the text between them by collation.
+pub fn natural_cmp(a: &OsStr, b: &OsStr, ignore_case: bool) -> Ordering {
+    compare_with(a, b, ignore_case, |a, b| natural_bytes(a, b).then_with(|| collate(a, b)))
+}
+
+/// `--sort version`: the order of `ls -v` and `sort -V`, e.g. `rs-1.9.tar.gz` < `rs-1.10~rc1.tar.gz` < `rs-1.10.tar.gz`.
+pub fn version_cmp(a: &OsStr, b: &OsStr, ignore_case: bool) -> Ordering {
+    compare_with(a, b, ignore_case, filevercmp)
+}
+
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s.iter().position(|c| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn split_text(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s.iter().position(u8::is_ascii_digit).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Compares two digit runs by value without overflowing, ignoring leading zeros.
+fn numeric_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let a = &a[a.iter().position(|&c| c != b'0').unwrap_or(a.len())..];
+    let b = &b[b.iter().position(|&c| c != b'0').unwrap_or(b.len())..];
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn natural_bytes(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    while !a.is_empty() && !b.is_empty() {
+        let ordering = if a[0].is_ascii_digit() && b[0].is_ascii_digit() {
+            let ((da, ra), (db, rb)) = (split_digits(a), split_digits(b));
+            (a, b) = (ra, rb);
+            numeric_cmp(da, db)
+        } else {
+            let ((ta, ra), (tb, rb)) = (split_text(a), split_text(b));
+            (a, b) = (ra, rb);
+            collate(ta, tb)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// GNU `filevercmp`: file suffixes such as `.tar.gz` are compared only when the rest of the names are equal.
+fn filevercmp(a: &[u8], b: &[u8]) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    // The empty name sorts first, then `.`, `..` and the other hidden names, then the rest.
+    match (a.is_empty(), b.is_empty()) {
+        (true, _) => return Ordering::Less,
+        (_, true) => return Ordering::Greater,
+        _ => {}
+    }
+    match (a[0] == b'.', b[0] == b'.') {
+        (true, false) => return Ordering::Less,
+        (false, true) => return Ordering::Greater,
+        (true, true) => {
+            for special in [&b"."[..], b".."] {
+                match (a == special, b == special) {
+                    (true, _) => return Ordering::Less,
+                    (_, true) => return Ordering::Greater,
+                    _ => {}
+                }
+            }
+        }
+        (false, false) => {}
+    }
+
+    let (pa, pb) = (&a[..a.len() - file_suffix_len(a)], &b[..b.len() - file_suffix_len(b)]);
+    let ordering = if pa == pb { Ordering::Equal } else { verrevcmp(pa, pb) };
+    ordering.then_with(|| verrevcmp(a, b))
+}
+
+/// Length of the trailing `(\.[A-Za-z~][A-Za-z0-9~]*)*` of a name; all of `.bashrc` is a suffix.
+fn file_suffix_len(s: &[u8]) -> usize {
+    let mut suffix_start = s.len();
+    let mut i = s.len();
+    while i > 0 {
+        i -= 1;
+        let c = s[i];
+        if c == b'.' {
+            match s.get(i + 1) {
+                Some(&next) if next.is_ascii_alphabetic() || next == b'~' => suffix_start = i,
+                _ => break,
+            }
+        } else if !(c.is_ascii_alphanumeric() || c == b'~') {
+            break;
+        }
+    }
+    s.len() - suffix_start
+}
+
+/// Sort weight of a non-digit byte: `~` before the end of a name, letters before other punctuation.
+fn version_weight(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+fn verrevcmp(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    while !a.is_empty() || !b.is_empty() {
+        while a.first().is_some_and(|c| !c.is_ascii_digit()) || b.first().is_some_and(|c| !c.is_ascii_digit()) {
+            let ordering = version_weight(a.first().copied()).cmp(&version_weight(b.first().copied()));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = a.get(1..).unwrap_or_default();
+            b = b.get(1..).unwrap_or_default();
+        }
+        let ((da, ra), (db, rb)) = (split_digits(a), split_digits(b));
+        let ordering = numeric_cmp(da, db);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        (a, b) = (ra, rb);
+    }
+    Ordering::Equal
+}
+
+/// The last commit time of every file tracked in the repositories containing `roots`, keyed by canonical path.
+///
+/// Each repository's history is read with a single `git log`; a directory gets the time of the newest commit
+/// touching anything below it. Roots outside a repository, or a missing `git`, simply contribute nothing.
+pub fn git_commit_dates(roots: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+    let mut dates = HashMap::new();
+    let mut toplevels = Vec::new();
+    for root in roots {
+        let Some(toplevel) = git_output(root, &["rev-parse", "--show-toplevel"]) else {
+            continue;
+        };
+        let toplevel = PathBuf::from(toplevel.trim_end_matches('\n'));
+        if toplevels.contains(&toplevel) {
+            continue;
+        }
+
+        // `%x00` marks each commit's timestamp line; the changed paths follow, relative to the top level.
+        let Some(log) = git_output(&toplevel, &["-c", "core.quotePath=false", "log", "--format=%x00%ct", "--name-only"]) else {
+            continue;
+        };
+        let mut time = UNIX_EPOCH;
+        for line in log.lines() {
+            if let Some(timestamp) = line.strip_prefix('\0') {
+                time = UNIX_EPOCH + Duration::from_secs(timestamp.parse().unwrap_or(0));
+            } else if !line.is_empty() {
+                // The log is newest first, so the first time a path (or any path below a directory) appears is its latest commit.
+                for path in toplevel.join(line).ancestors().take_while(|p| p.starts_with(&toplevel)) {
+                    dates.entry(path.to_path_buf()).or_insert(time);
+                }
+            }
+        }
+        toplevels.push(toplevel);
+    }
+    dates
+}
+
+fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `path` with its directory resolved the way git reports the top level, without following a final symlink.
+pub fn canonical_path(path: &Path) -> Option<PathBuf> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Some(fs::canonicalize(parent).ok()?.join(path.file_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str], cmp: fn(&OsStr, &OsStr, bool) -> Ordering) -> Vec<String> {
+        let mut names: Vec<&OsStr> = names.iter().rev().map(OsStr::new).collect();
+        // `sort` falls back to comparing bytes when the keys are equal, as `sort_entries` does.
+        names.sort_by(|a, b| cmp(a, b, false).then_with(|| a.cmp(b)));
+        names.iter().map(|name| name.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn version_order_matches_sort_v() {
+        // The output of `LC_ALL=C sort -V` (GNU coreutils 9.1) for these names.
+        let expected = [
+            ".", "..", ".a2", ".a10", ".bashrc", ".hidden", ".bash_profile", ".b_", "1.0~beta", "1.0", "1.0a", "1.0-1",
+            "1.0.1", "B", "README", "README.md", "a~", "a", "a0", "a.b~", "a.b", "a1b", "a2", "a10", "a-b", "a.b_c", "b",
+            "file.txt", "file1.txt", "file2.txt", "file10.txt", "foo-1.02.3", "foo-1.2.3", "foo-1.2.10", "lib2.so.1",
+            "lib2.so.10", "lib10.so.1", "rs-1.9.tar.gz", "rs-1.10~rc1.tar.gz", "rs-1.10.tar.gz~", "rs-1.10.tar.gz",
+            "x.tar.gz", "x.tgz",
+        ];
+        assert_eq!(sorted(&expected, version_cmp), expected);
+    }
+
+    #[test]
+    fn version_order_of_empty_and_equal_versions() {
+        assert_eq!(version_cmp(OsStr::new(""), OsStr::new("."), false), Ordering::Less);
+        assert_eq!(version_cmp(OsStr::new("0a"), OsStr::new("00a"), false), Ordering::Equal);
+        assert_eq!(version_cmp(OsStr::new("a"), OsStr::new("a0"), false), Ordering::Equal);
+        // A hidden name that is all suffix has an empty version, so it sorts before one that is not.
+        assert_eq!(version_cmp(OsStr::new(".h"), OsStr::new(".b_"), false), Ordering::Less);
+        assert_eq!(version_cmp(OsStr::new("a"), OsStr::new("A"), true), Ordering::Greater);
+    }
+
+    #[test]
+    fn natural_order() {
+        let expected = ["file", "file1", "file02", "file2", "file10", "file10a", "file10b", "file100", "file9999999999999999999999"];
+        assert_eq!(sorted(&expected, natural_cmp), expected);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::os::unix::fs::MetadataExt;
+use std::time::SystemTime;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use encoding_rs::Encoding;
+use sha2::{Digest, Sha256};
+
+use crate::{display_path, encoding, format_size, format_time, guess_language, is_text_file, mode_string, Config, DirEntryExt, TimeField};
+
+/// A per-entry value that can appear in `--columns` or as a `{field}` in `--template`.
+#[derive(Debug, Clone, Copy)]
+pub enum Column {
+    Name,
+    Path,
+    Size,
+    Mtime,
+    Atime,
+    Ctime,
+    Mode,
+    Owner,
+    Group,
+    Inode,
+    Nlink,
+    Mime,
+    Lang,
+    Lines,
+    Tokens,
+    Encoding,
+    Hash,
+}
+
+pub const COLUMN_NAMES: &str = "name, path, size, mtime, atime, ctime, mode, owner, group, inode, nlink, mime, lang, lines, tokens, encoding, hash";
+
+impl Column {
+    fn from_name(s: &str) -> Option<Self> {
+        Some(match s {
+            "name" => Column::Name,
+            "path" => Column::Path,
+            "size" => Column::Size,
+            "mtime" => Column::Mtime,
+            "atime" => Column::Atime,
+            "ctime" => Column::Ctime,
+            "mode" => Column::Mode,
+            "owner" => Column::Owner,
+            "group" => Column::Group,
+            "inode" => Column::Inode,
+            "nlink" => Column::Nlink,
+            "mime" => Column::Mime,
+            "lang" => Column::Lang,
+            "lines" => Column::Lines,
+            "tokens" => Column::Tokens,
+            "encoding" => Column::Encoding,
+            "hash" => Column::Hash,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Field(Column, Option<String>),
+    /// A right-aligned size column, as produced by `--columns`.
+    AlignedSize,
+}
+
+/// A parsed `--template` (or `--columns`) line: literal text interleaved with `{field}` / `{field:spec}` placeholders.
+///
+/// Specs: `size:h` human-readable in the `--size-style` (default) or `size:b` bytes; `mtime`/`atime`/`ctime` follow `--time-style`
+/// or take a strftime format in local time (`{mtime:%Y-%m-%d}`); `hash:N` keeps the first N hex digits; `mode:o` is octal rather than
+/// `ls -l` style; `owner:n`/`group:n` are numeric ids. `{{` and `}}` are literal braces.
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("unclosed '{{{}' in template", placeholder)),
+                        }
+                    }
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec.to_string())),
+                        None => (placeholder.as_str(), None),
+                    };
+                    let column = Column::from_name(name)
+                        .ok_or_else(|| format!("unknown field '{}' (expected one of: {})", name, COLUMN_NAMES))?;
+                    validate_spec(column, spec.as_deref())?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(column, spec));
+                }
+                '}' => return Err("unmatched '}' in template (use '}}' for a literal brace)".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    /// `--columns name,size,mtime` is shorthand for the template `{name}  {size}  {mtime}`, with sizes right-aligned.
+    pub fn from_columns(list: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let column = Column::from_name(name)
+                .ok_or_else(|| format!("unknown column '{}' (expected one of: {})", name, COLUMN_NAMES))?;
+            if !segments.is_empty() {
+                segments.push(Segment::Literal("  ".to_string()));
+            }
+            segments.push(match column {
+                Column::Size => Segment::AlignedSize,
+                _ => Segment::Field(column, None),
+            });
+        }
+        if segments.is_empty() {
+            return Err("no columns given".to_string());
+        }
+        Ok(Template { segments })
+    }
+
+    pub fn render(&self, entry: &DirEntryExt, config: &Config) -> String {
+        let mut line = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => line.push_str(text),
+                Segment::Field(column, spec) => line.push_str(&field_value(*column, spec.as_deref(), entry, config)),
+                Segment::AlignedSize => {
+                    let size = format_size(entry.size, config.size_style);
+                    line.push_str(&format!("{:>width$}", size, width = config.size_style.width()));
+                }
+            }
+        }
+        line
+    }
+}
+
+fn validate_spec(column: Column, spec: Option<&str>) -> Result<(), String> {
+    let Some(spec) = spec else {
+        return Ok(());
+    };
+    match column {
+        Column::Size if spec == "h" || spec == "b" => Ok(()),
+        Column::Mtime | Column::Atime | Column::Ctime => {
+            if StrftimeItems::new(spec).any(|item| matches!(item, Item::Error)) {
+                Err(format!("invalid time format '{}'", spec))
+            } else {
+                Ok(())
+            }
+        }
+        Column::Hash if spec.parse::<usize>().is_ok() => Ok(()),
+        Column::Mode if spec == "o" => Ok(()),
+        Column::Owner | Column::Group if spec == "n" => Ok(()),
+        _ => Err(format!("field '{:?}' does not accept the format '{}'", column, spec).to_lowercase()),
+    }
+}
+
+fn field_value(column: Column, spec: Option<&str>, entry: &DirEntryExt, config: &Config) -> String {
+    let metadata = &entry.metadata;
+    match column {
+        // In a `--flat` listing there is no tree to show where a file lives, so `name` is its path.
+        Column::Name if config.flat => display_path_with_link(entry, config),
+        Column::Name => entry.display_name(),
+        Column::Path => display_path_with_link(entry, config),
+        Column::Size => match spec {
+            Some("b") => entry.size.to_string(),
+            _ => format_size(entry.size, config.size_style),
+        },
+        Column::Mtime => column_time(TimeField::Mtime.of(metadata), spec, config),
+        Column::Atime => column_time(TimeField::Atime.of(metadata), spec, config),
+        Column::Ctime => column_time(TimeField::Ctime.of(metadata), spec, config),
+        Column::Mode => match spec {
+            Some("o") => format!("{:04o}", metadata.mode() & 0o7777),
+            _ => mode_string(metadata),
+        },
+        Column::Owner => id_name(metadata.uid(), &config.user_names, spec),
+        Column::Group => id_name(metadata.gid(), &config.group_names, spec),
+        Column::Inode => metadata.ino().to_string(),
+        Column::Nlink => metadata.nlink().to_string(),
+        Column::Mime => entry.filetype_desc.clone(),
+        Column::Lang => guess_language(&entry.path),
+        Column::Lines => text_only(entry, config, count_lines).map(|n| n.to_string()).unwrap_or_else(|| "-".to_string()),
+        Column::Tokens => text_only(entry, config, estimate_file_tokens).map(|n| n.to_string()).unwrap_or_else(|| "-".to_string()),
+        Column::Encoding if entry.is_dir || entry.unfollowed_link(config) || !is_text_file(&entry.path) => "-".to_string(),
+        Column::Encoding => config.file_encoding(&entry.path).name().to_string(),
+        Column::Hash => match hash_file(entry, config) {
+            Some(hex) => {
+                let len = spec.and_then(|s| s.parse().ok()).unwrap_or(hex.len()).min(hex.len());
+                hex[..len].to_string()
+            }
+            None => "-".to_string(),
+        },
+    }
+}
+
+/// The entry's path as `display_path` gives it, followed by `-> target` for symbolic links.
+fn display_path_with_link(entry: &DirEntryExt, config: &Config) -> String {
+    let path = display_path(config, &entry.path);
+    match entry.link_target {
+        Some(ref target) => format!("{} -> {}", path, target.display()),
+        None => path,
+    }
+}
+
+/// A user or group name, falling back to the numeric id when it is unknown or `:n` asks for it.
+fn id_name(id: u32, names: &HashMap<u32, String>, spec: Option<&str>) -> String {
+    match (spec, names.get(&id)) {
+        (None, Some(name)) => name.clone(),
+        _ => id.to_string(),
+    }
+}
+
+/// A time column in the `--time-style` format, unless the placeholder gives its own strftime spec.
+fn column_time(time: Option<SystemTime>, spec: Option<&str>, config: &Config) -> String {
+    match (time, spec) {
+        (Some(time), Some(format)) => DateTime::<Local>::from(time).format(format).to_string(),
+        (time, _) => format_time(time, &config.time_style),
+    }
+}
+
+/// Runs `count` on regular text files, read in their encoding; directories, binaries, unreadable files and links
+/// without `--follow` have no value.
+pub fn text_only(
+    entry: &DirEntryExt,
+    config: &Config,
+    count: fn(&DirEntryExt, &'static Encoding) -> io::Result<usize>,
+) -> Option<usize> {
+    if entry.is_dir || entry.unfollowed_link(config) || !is_text_file(&entry.path) {
+        return None;
+    }
+    count(entry, config.file_encoding(&entry.path)).ok()
+}
+
+pub fn count_lines(entry: &DirEntryExt, encoding: &'static Encoding) -> io::Result<usize> {
+    let mut reader = encoding::reader(&entry.path, encoding)?;
+    let mut count = 0;
+    let mut buffer = Vec::new();
+    while reader.read_until(b'\n', &mut buffer)? > 0 {
+        count += 1;
+        buffer.clear();
+    }
+    Ok(count)
+}
+
+pub fn estimate_file_tokens(entry: &DirEntryExt, encoding: &'static Encoding) -> io::Result<usize> {
+    Ok(estimate_tokens(&encoding::read_to_string(&entry.path, encoding)?))
+}
+
+/// A rough LLM token count: about four characters per token, which is close enough for budgeting context.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Hex SHA-256 of a file's contents, or `None` for directories, unreadable files and links without `--follow`.
+fn hash_file(entry: &DirEntryExt, config: &Config) -> Option<String> {
+    if entry.is_dir || entry.unfollowed_link(config) {
+        return None;
+    }
+    let mut file = fs::File::open(&entry.path).ok()?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).ok()?;
+    Some(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,470 @@
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::matcher::Matcher;
+use crate::{
+    describe_sort, display_path, encoding, file_details, filter_and_print_content, format_size, is_text_file, read_entries, write_file_content,
+    Config, DirEntryExt, SortDirection, SortField, SortKey,
+};
+
+const HELP: &str = "↑↓ move  →/enter open  ← close  space mark  t type  s sort  r reverse  / search  n/N next/prev  PgUp/PgDn scroll  e export  q quit";
+const DEFAULT_EXPORT: &str = "rs-export.md";
+
+struct Row {
+    entry: DirEntryExt,
+    depth: usize,
+}
+
+enum Prompt {
+    Type,
+    Search,
+    Export,
+}
+
+struct App {
+    config: Config,
+    rows: Vec<Row>,
+    expanded: HashSet<PathBuf>,
+    marked: BTreeSet<PathBuf>,
+    list_state: ListState,
+    preview_path: Option<PathBuf>,
+    preview: Vec<String>,
+    preview_scroll: u16,
+    prompt: Option<(Prompt, String)>,
+    status: String,
+}
+
+/// Runs the interactive browser until the user quits.
+///
+/// The tree is built with `read_entries`, so exclusions, type filters and sorting behave exactly as in
+/// `print_tree`; the preview pane is the output of `filter_and_print_content` for the selected file.
+pub fn run(mut config: Config) -> io::Result<()> {
+    // Exports are always markdown, and ANSI escapes have no place in either the preview or the export.
+    config.output_format = "markdown".to_string();
+    config.highlight = false;
+    // Previews are rendered over and over; a run-wide match budget would run dry after a few of them.
+    config.max_total_matches = 0;
+
+    let mut app = App {
+        config,
+        rows: Vec::new(),
+        expanded: HashSet::new(),
+        marked: BTreeSet::new(),
+        list_state: ListState::default(),
+        preview_path: None,
+        preview: Vec::new(),
+        preview_scroll: 0,
+        prompt: None,
+        status: HELP.to_string(),
+    };
+    app.rebuild();
+
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            self.refresh_preview();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            if self.prompt.is_some() {
+                self.handle_prompt_key(key.code);
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+                KeyCode::Home => self.select(0),
+                KeyCode::End => self.select(self.rows.len().saturating_sub(1)),
+                KeyCode::Right | KeyCode::Enter | KeyCode::Char('l') => self.expand(),
+                KeyCode::Left | KeyCode::Char('h') => self.collapse(),
+                KeyCode::PageDown => self.preview_scroll = self.preview_scroll.saturating_add(10),
+                KeyCode::PageUp => self.preview_scroll = self.preview_scroll.saturating_sub(10),
+                KeyCode::Char(' ') => self.toggle_mark(),
+                KeyCode::Char('s') => {
+                    let key = self.primary_sort_key();
+                    key.field = next_sort_field(&key.field);
+                    self.rebuild();
+                    self.status = format!("Sorting: {}", describe_sort(&self.config.sort_keys));
+                }
+                KeyCode::Char('r') => {
+                    let key = self.primary_sort_key();
+                    key.direction = match key.direction {
+                        SortDirection::Asc => SortDirection::Desc,
+                        SortDirection::Desc => SortDirection::Asc,
+                    };
+                    self.rebuild();
+                    self.status = format!("Sorting: {}", describe_sort(&self.config.sort_keys));
+                }
+                KeyCode::Char('t') => self.prompt = Some((Prompt::Type, self.config.file_types.join(" "))),
+                KeyCode::Char('/') => {
+                    let current = self.config.content_filter.as_ref().map(|m| m.patterns().join("|")).unwrap_or_default();
+                    self.prompt = Some((Prompt::Search, current));
+                }
+                KeyCode::Char('n') => self.find_next(true),
+                KeyCode::Char('N') => self.find_next(false),
+                KeyCode::Char('e') => self.prompt = Some((Prompt::Export, DEFAULT_EXPORT.to_string())),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_prompt_key(&mut self, code: KeyCode) {
+        let Some((_, input)) = self.prompt.as_mut() else {
+            return;
+        };
+        match code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => {
+                self.prompt = None;
+                self.status = HELP.to_string();
+            }
+            KeyCode::Enter => {
+                let (kind, input) = self.prompt.take().unwrap();
+                self.submit_prompt(kind, input.trim());
+            }
+            _ => {}
+        }
+    }
+
+    fn submit_prompt(&mut self, kind: Prompt, input: &str) {
+        match kind {
+            Prompt::Type => {
+                self.config.file_types = input.split_whitespace().map(str::to_string).collect();
+                self.rebuild();
+                self.status = if self.config.file_types.is_empty() {
+                    "Filters cleared".to_string()
+                } else {
+                    format!("Filters: {:?}", self.config.file_types)
+                };
+            }
+            Prompt::Search => {
+                if input.is_empty() {
+                    self.config.content_filter = None;
+                    self.status = "Search cleared".to_string();
+                } else {
+                    // The search replaces any -p/--and-pattern from the command line but keeps -i, -F, -w and -v.
+                    match Matcher::new(&[input.to_string()], &[], self.config.match_options) {
+                        Ok(matcher) => {
+                            self.config.content_filter = Some(matcher);
+                            self.find_next(true);
+                        }
+                        Err(e) => self.status = format!("Invalid pattern: {}", e),
+                    }
+                }
+                self.preview_path = None;
+            }
+            Prompt::Export => {
+                let path = if input.is_empty() { DEFAULT_EXPORT } else { input };
+                self.status = match self.export(Path::new(path)) {
+                    Ok(count) => format!("Exported {} file(s) to {}", count, path),
+                    Err(e) => format!("Export failed: {}", e),
+                };
+            }
+        }
+    }
+
+    /// The first `--sort` key, which `s` and `r` change; the rest of the chain only breaks ties.
+    fn primary_sort_key(&mut self) -> &mut SortKey {
+        if self.config.sort_keys.is_empty() {
+            self.config.sort_keys.push(SortKey { field: SortField::Name, direction: SortDirection::Asc });
+        }
+        &mut self.config.sort_keys[0]
+    }
+
+    /// Re-reads the tree from disk, re-opening every directory that was expanded and keeping the selection.
+    fn rebuild(&mut self) {
+        // The type filters may have changed.
+        self.config.visible_dirs.borrow_mut().clear();
+        let selected = self.selected_row().map(|row| row.entry.path.clone());
+        self.rows.clear();
+        let root = self.config.root().to_path_buf();
+        self.push_children(&root, 0);
+
+        let index = selected
+            .and_then(|path| self.rows.iter().position(|row| row.entry.path == path))
+            .unwrap_or(0);
+        self.select(index);
+        self.preview_path = None;
+    }
+
+    fn push_children(&mut self, dir: &Path, depth: usize) {
+        let entries = match read_entries(dir, &self.config) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.status = format!("Error reading directory '{}': {}", dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries {
+            let path = entry.path.clone();
+            let crosses_device = entry.mount_point && self.config.one_file_system;
+            let open = entry.is_dir && !entry.cycle && !crosses_device && self.expanded.contains(&path);
+            self.rows.push(Row { entry, depth });
+            if open {
+                self.push_children(&path, depth + 1);
+            }
+        }
+    }
+
+    fn selected_row(&self) -> Option<&Row> {
+        self.list_state.selected().and_then(|i| self.rows.get(i))
+    }
+
+    fn select(&mut self, index: usize) {
+        if self.rows.is_empty() {
+            self.list_state.select(None);
+        } else {
+            self.list_state.select(Some(index.min(self.rows.len() - 1)));
+        }
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let current = self.list_state.selected().unwrap_or(0);
+        self.select(current.saturating_add_signed(delta));
+    }
+
+    fn expand(&mut self) {
+        let Some(row) = self.selected_row() else {
+            return;
+        };
+        if row.entry.is_dir && self.expanded.insert(row.entry.path.clone()) {
+            self.rebuild();
+        }
+    }
+
+    /// Closes the selected directory, or jumps to the parent directory when on a file or a closed directory.
+    fn collapse(&mut self) {
+        let Some(index) = self.list_state.selected() else {
+            return;
+        };
+        let row = &self.rows[index];
+        if row.entry.is_dir && self.expanded.remove(&row.entry.path) {
+            self.rebuild();
+            return;
+        }
+        let depth = row.depth;
+        if let Some(parent) = (0..index).rev().find(|&i| self.rows[i].depth < depth) {
+            self.select(parent);
+        }
+    }
+
+    fn toggle_mark(&mut self) {
+        let Some(row) = self.selected_row() else {
+            return;
+        };
+        if row.entry.is_dir {
+            return;
+        }
+        let path = row.entry.path.clone();
+        if !self.marked.remove(&path) {
+            self.marked.insert(path);
+        }
+        self.status = format!("{} file(s) marked", self.marked.len());
+        self.move_selection(1);
+    }
+
+    /// Moves to the next (or previous) visible entry whose name or content matches the search pattern.
+    fn find_next(&mut self, forward: bool) {
+        let Some(matcher) = self.config.content_filter.clone() else {
+            self.status = "No search pattern (press /)".to_string();
+            return;
+        };
+        let len = self.rows.len();
+        let start = self.list_state.selected().unwrap_or(0);
+        for step in 1..=len {
+            let index = if forward { (start + step) % len } else { (start + len - step % len) % len };
+            if self.row_matches(&self.rows[index], &matcher) {
+                self.select(index);
+                self.status = format!("/{}", matcher);
+                return;
+            }
+        }
+        self.status = format!("/{} not found", matcher);
+    }
+
+    fn row_matches(&self, row: &Row, matcher: &Matcher) -> bool {
+        let name = row.entry.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
+        if matcher.is_match(&name) {
+            return true;
+        }
+        row.entry.has_readable_content(&self.config)
+            && encoding::read_to_string(&row.entry.path, self.config.file_encoding(&row.entry.path))
+                .map(|text| {
+                    let lines: Vec<String> = text.lines().map(str::to_string).collect();
+                    matcher.file_matches(&lines) && matcher.search(&lines).iter().any(Option::is_some)
+                })
+                .unwrap_or(false)
+    }
+
+    fn refresh_preview(&mut self) {
+        let Some(row) = self.selected_row() else {
+            self.preview.clear();
+            self.preview_path = None;
+            return;
+        };
+        if self.preview_path.as_ref() == Some(&row.entry.path) {
+            return;
+        }
+
+        let lines = preview_lines(&row.entry, &self.config);
+        self.preview_path = Some(row.entry.path.clone());
+        self.preview = lines;
+        self.preview_scroll = 0;
+    }
+
+    /// Writes the marked files, with their contents, as a markdown document.
+    fn export(&self, path: &Path) -> io::Result<usize> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        let root = self.config.root();
+        writeln!(out, "# 📁 Project Source Tree: {}", root.file_name().unwrap_or_else(|| OsStr::new(".")).to_string_lossy())?;
+        writeln!(out, "Generated on {}", Utc::now().to_rfc3339())?;
+        writeln!(out, "Selected files: {}", self.marked.len())?;
+        writeln!(out)?;
+
+        for file in &self.marked {
+            let metadata = if self.config.follow_links { fs::metadata(file)? } else { fs::symlink_metadata(file)? };
+            let mut entry = DirEntryExt::new(file.clone(), &metadata, self.config.time_field);
+            entry.link_target = fs::read_link(file).ok();
+            let rel = file.strip_prefix(root).unwrap_or(file);
+            writeln!(out, "📄 {} {}", rel.display(), file_details(&entry, &self.config))?;
+            if entry.has_readable_content(&self.config) {
+                write_file_content(&mut out, file, "", &self.config)?;
+            } else if !entry.unfollowed_link(&self.config) && entry.size > self.config.max_file_size {
+                writeln!(out, "  (File not displayed - {})", format_size(entry.size, self.config.size_style))?;
+            }
+        }
+
+        writeln!(out)?;
+        writeln!(out, "_End of source tree_")?;
+        out.flush()?;
+        Ok(self.marked.len())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let outer = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.area());
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .split(outer[0]);
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let name = row.entry.path.file_name().unwrap_or_else(|| OsStr::new("")).to_string_lossy();
+                let indent = "  ".repeat(row.depth);
+                let text = if row.entry.is_dir {
+                    let arrow = if self.expanded.contains(&row.entry.path) { "▾" } else { "▸" };
+                    format!("{}{} {}/", indent, arrow, name)
+                } else {
+                    let mark = if self.marked.contains(&row.entry.path) { "●" } else { " " };
+                    format!("{}{} {}", indent, mark, row.entry.display_name())
+                };
+                let style = if row.entry.is_dir {
+                    Style::default().fg(Color::Blue).add_modifier(Modifier::BOLD)
+                } else if self.marked.contains(&row.entry.path) {
+                    Style::default().fg(Color::Green)
+                } else {
+                    Style::default()
+                };
+                ListItem::new(text).style(style)
+            })
+            .collect();
+
+        let root = self.config.root().display().to_string();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" {} ", root)))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, panes[0], &mut self.list_state);
+
+        let title = self
+            .preview_path
+            .as_ref()
+            .map(|p| format!(" {} ", display_path(&self.config, p)))
+            .unwrap_or_default();
+        let preview = Paragraph::new(self.preview.iter().map(|l| Line::raw(l.as_str())).collect::<Vec<_>>())
+            .block(Block::bordered().title(title))
+            .scroll((self.preview_scroll, 0));
+        frame.render_widget(preview, panes[1]);
+
+        let status = match &self.prompt {
+            Some((Prompt::Type, input)) => format!("type filters: {}", input),
+            Some((Prompt::Search, input)) => format!("/{}", input),
+            Some((Prompt::Export, input)) => format!("export marked files to: {}", input),
+            None => self.status.clone(),
+        };
+        frame.render_widget(Paragraph::new(status).style(Style::default().add_modifier(Modifier::DIM)), outer[1]);
+    }
+}
+
+/// The preview pane text for an entry: item count for directories, details and filtered content for files.
+pub fn preview_lines(entry: &DirEntryExt, config: &Config) -> Vec<String> {
+    let mut lines = Vec::new();
+    if entry.is_dir {
+        let count = fs::read_dir(&entry.path).map(|d| d.count()).unwrap_or(0);
+        lines.push(format!("{} items", count));
+    } else {
+        lines.push(file_details(entry, config));
+        lines.push(String::new());
+        if entry.unfollowed_link(config) {
+            lines.push("(Symbolic link - contents shown with --follow)".to_string());
+        } else if entry.size > config.max_file_size {
+            lines.push(format!("(File not displayed - {})", format_size(entry.size, config.size_style)));
+        } else if !is_text_file(&entry.path) {
+            lines.push("(Binary file not displayed)".to_string());
+        } else {
+            let mut buffer = Vec::new();
+            let _ = filter_and_print_content(&mut buffer, &entry.path, "", config);
+            lines.extend(String::from_utf8_lossy(&buffer).lines().map(|l| l.replace('\t', "    ")));
+        }
+    }
+    lines
+}
+
+fn next_sort_field(field: &SortField) -> SortField {
+    match field {
+        SortField::Name => SortField::Natural,
+        SortField::Natural => SortField::Version,
+        SortField::Version => SortField::Date,
+        SortField::Date => SortField::Created,
+        SortField::Created => SortField::GitDate,
+        SortField::GitDate => SortField::Size,
+        SortField::Size => SortField::Lines,
+        SortField::Lines => SortField::Tokens,
+        SortField::Tokens => SortField::Type,
+        SortField::Type => SortField::Ext,
+        SortField::Ext => SortField::Group,
+        SortField::Group => SortField::Name,
+    }
+}
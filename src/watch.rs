@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::{render, Config};
+
+/// How long to wait for a burst of events (an editor save, a `git checkout`) to settle before re-rendering.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Watches the mapped directories with inotify and re-renders the tree on every change.
+///
+/// Only directories that `print_tree` would actually read are watched: excluded names are skipped
+/// and nothing deeper than `--depth` (or a `--depth-for` rule) allows is registered, so events outside the rendered area are never seen.
+pub fn run(config: &Config) -> io::Result<()> {
+    let mut inotify = Inotify::init()?;
+    let mut watched: HashMap<WatchDescriptor, (PathBuf, usize)> = HashMap::new();
+    for root in &config.roots {
+        add_watches(&mut inotify, &mut watched, root, config, 1)?;
+    }
+
+    redraw(config, true);
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        let mut changes = Vec::new();
+        collect_events(inotify.read_events_blocking(&mut buffer)?, &mut changes);
+
+        // Drain whatever else arrives while the burst settles so one save triggers one render.
+        thread::sleep(DEBOUNCE);
+        loop {
+            match inotify.read_events(&mut buffer) {
+                Ok(events) => collect_events(events, &mut changes),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut relevant = false;
+        for (wd, mask, name) in changes {
+            if mask.contains(EventMask::IGNORED) {
+                watched.remove(&wd);
+                continue;
+            }
+            let Some((dir, depth)) = watched.get(&wd).cloned() else {
+                continue;
+            };
+            let Some(name) = name else {
+                continue;
+            };
+            if config.exclude_dirs.contains(&name.to_string_lossy().to_string()) {
+                continue;
+            }
+            relevant = true;
+
+            if mask.contains(EventMask::ISDIR) && (mask.contains(EventMask::CREATE) || mask.contains(EventMask::MOVED_TO)) {
+                add_watches(&mut inotify, &mut watched, &dir.join(&name), config, depth + 1)?;
+            }
+        }
+
+        if relevant {
+            redraw(config, false);
+        }
+    }
+}
+
+fn collect_events<'a>(
+    events: impl Iterator<Item = inotify::Event<&'a OsStr>>,
+    changes: &mut Vec<(WatchDescriptor, EventMask, Option<OsString>)>,
+) {
+    for event in events {
+        changes.push((event.wd, event.mask, event.name.map(|n| n.to_os_string())));
+    }
+}
+
+/// Registers `dir` (at tree level `depth`, the root being 1) and every subdirectory `print_tree` would descend into.
+fn add_watches(
+    inotify: &mut Inotify,
+    watched: &mut HashMap<WatchDescriptor, (PathBuf, usize)>,
+    dir: &Path,
+    config: &Config,
+    depth: usize,
+) -> io::Result<()> {
+    if !config.within_depth(dir, depth) {
+        return Ok(());
+    }
+
+    let mask = WatchMask::CREATE
+        | WatchMask::MODIFY
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::ATTRIB;
+    let wd = match inotify.watches().add(dir, mask) {
+        Ok(wd) => wd,
+        // The directory vanished or is unreadable; print_tree will report it on the next render.
+        Err(e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    watched.insert(wd, (dir.to_path_buf(), depth));
+
+    let read_dir = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+    let dir_dev = fs::metadata(dir).map(|m| m.dev()).ok();
+    for entry in read_dir.flatten() {
+        if config.exclude_dirs.contains(&entry.file_name().to_string_lossy().to_string()) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_dir() || (config.one_file_system && dir_dev != Some(metadata.dev())) {
+            continue;
+        }
+        add_watches(inotify, watched, &entry.path(), config, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Clears the screen on a terminal; when piped, renders are simply separated by a blank line.
+fn redraw(config: &Config, first: bool) {
+    if io::stdout().is_terminal() {
+        print!("\x1b[2J\x1b[H");
+        let _ = io::stdout().flush();
+    } else if !first {
+        println!();
+    }
+    render(config);
+}